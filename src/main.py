@@ -63,6 +63,8 @@ class Options:
     use_network: bool = False
     network_debug: bool = False
     network_priority: float = 1.0
+    network_tags: List[str] = field(default_factory=list)
+    network_docker_image: Optional[str] = None
 
 
 def restricted_float(lo: float, hi: float) -> Callable[[str], float]:
@@ -376,6 +378,8 @@ def run_inner(options: Options, heartbeat: Callable[[], None]) -> List[int]:
                     perm_index,
                     feedback_queue,
                     options.network_priority,
+                    options.network_tags,
+                    options.network_docker_image,
                 )
                 net_conns.append((thread, queue))
                 if first_stats is None:
@@ -621,6 +625,24 @@ def main() -> None:
             Each server runs with a priority threshold, which defaults to 0.1,
             below which they will not run permuter jobs at all.""",
     )
+    parser.add_argument(
+        "--require-tag",
+        dest="network_tags",
+        metavar="TAG",
+        action="append",
+        default=[],
+        help="""Only run on permuter@home servers that advertise the given
+            capability tag, e.g. a toolchain or architecture. Can be passed
+            multiple times.""",
+    )
+    parser.add_argument(
+        "--docker-image",
+        dest="network_docker_image",
+        metavar="NAME",
+        help="""Run permuter@home jobs in the docker image with the given name,
+            as configured on the central server, e.g. for a different
+            platform's toolchain. Defaults to the server's default image.""",
+    )
 
     args = parser.parse_args()
 
@@ -645,6 +667,8 @@ def main() -> None:
         use_network=args.use_network,
         network_debug=args.network_debug,
         network_priority=args.network_priority,
+        network_tags=args.network_tags,
+        network_docker_image=args.network_docker_image,
     )
 
     run(options)
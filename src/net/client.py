@@ -1,7 +1,9 @@
 from multiprocessing import Queue
+import random
 import re
 import threading
-from typing import Optional, Tuple
+import time
+from typing import List, Optional, Tuple
 import zlib
 
 from ..candidate import CandidateResult
@@ -22,10 +24,17 @@ from ..profiler import Profiler
 from .core import (
     PermuterData,
     SocketPort,
+    connect,
     json_prop,
     permuter_data_to_json,
 )
 
+# How often to try reconnecting after the connection to the controller drops,
+# and how long to wait before each attempt. The controller only keeps the
+# session around for a grace period, so there's no point in trying for long.
+_RECONNECT_ATTEMPTS: int = 5
+_RECONNECT_DELAY_SEC: float = 5.0
+
 
 def _profiler_from_json(obj: dict) -> Profiler:
     ret = Profiler()
@@ -80,7 +89,9 @@ def _make_script_portable(source: str) -> str:
     return "\n".join(lines)
 
 
-def make_portable_permuter(permuter: Permuter) -> PermuterData:
+def make_portable_permuter(
+    permuter: Permuter, required_tags: List[str], docker_image: Optional[str]
+) -> PermuterData:
     with open(permuter.scorer.target_o, "rb") as f:
         target_o_bin = f.read()
 
@@ -98,29 +109,36 @@ def make_portable_permuter(permuter: Permuter) -> PermuterData:
         compile_script=compile_script,
         source=permuter.source,
         target_o_bin=target_o_bin,
+        required_tags=required_tags,
+        docker_image=docker_image,
     )
 
 
 class Connection:
     _port: SocketPort
+    _request: dict
     _permuter_data: PermuterData
     _perm_index: int
     _task_queue: "Queue[Task]"
     _feedback_queue: "Queue[Feedback]"
+    _resume_token: Optional[str]
 
     def __init__(
         self,
         port: SocketPort,
+        request: dict,
         permuter_data: PermuterData,
         perm_index: int,
         task_queue: "Queue[Task]",
         feedback_queue: "Queue[Feedback]",
     ) -> None:
         self._port = port
+        self._request = request
         self._permuter_data = permuter_data
         self._perm_index = perm_index
         self._task_queue = task_queue
         self._feedback_queue = feedback_queue
+        self._resume_token = None
 
     def _send_permuter(self) -> None:
         data = self._permuter_data
@@ -180,11 +198,65 @@ class Connection:
 
         raise ValueError(f"Invalid message type {msg_type}")
 
+    def _receive_previous_best(self, msg: dict) -> None:
+        """Handle the best result from earlier sessions on the same function,
+        if the reply to the permuter data includes one."""
+        if "previous_best" not in msg:
+            return
+        obj = json_prop(msg, "previous_best", dict)
+        source: Optional[str] = None
+        if obj.get("has_source") == True:
+            compressed_source = self._port.receive()
+            source = zlib.decompress(compressed_source).decode("utf-8")
+        result = _result_from_json(obj, source)
+        self._feedback(WorkDone(self._perm_index, result), "previous session")
+
+    def _start(self) -> bool:
+        """Send the permuter and handle the reply to it. Returns whether the
+        controller generates work on its own."""
+        self._send_permuter()
+        reply = self._port.receive_json()
+        self._receive_previous_best(reply)
+        if "resume_token" in reply:
+            self._resume_token = json_prop(reply, "resume_token", str)
+        if reply.get("capable_servers") == 0:
+            text = "no connected server can run this job; waiting for one"
+            self._feedback(Message(text), None)
+
+        return reply.get("generated_seeds") == True
+
+    def _reconnect(self) -> None:
+        """Reconnect after the connection dropped, picking up where we left
+        off if the controller still has our session, or starting over if not.
+        Raises EOFError if reconnecting fails."""
+        token = self._resume_token
+        assert token is not None
+        self._feedback(Message("connection lost, reconnecting"), None)
+        self._port.shutdown()
+        self._port.close()
+        for _ in range(_RECONNECT_ATTEMPTS):
+            time.sleep(_RECONNECT_DELAY_SEC)
+            try:
+                self._port = connect()
+            except EOFError:
+                continue
+            self._port.send_json(dict(self._request, resume=token))
+            self._port.receive_json()
+            resumed = json_prop(self._port.receive_json(), "resumed", bool)
+            if resumed:
+                self._feedback(Message("reconnected"), None)
+            else:
+                self._feedback(Message("reconnected, starting over"), None)
+                self._start()
+            return
+        raise EOFError
+
     def run(self) -> None:
         finish_reason: Optional[str] = None
         try:
-            self._send_permuter()
-            self._port.receive_json()
+            # If the controller generates work on its own, all that's left is
+            # to receive results.
+            generated_seeds = self._start()
 
             finished = False
 
@@ -194,7 +266,13 @@ class Connection:
             # single thread, however it could cause deadlocks if the server
             # receiver stops reading because we aren't reading fast enough.
             while True:
-                if not self._receive_one():
+                try:
+                    if not self._receive_one() or generated_seeds:
+                        continue
+                except EOFError:
+                    if self._resume_token is None:
+                        raise
+                    self._reconnect()
                     continue
                 self._feedback(NeedMoreWork(), None)
 
@@ -217,7 +295,13 @@ class Connection:
                                 "seed": task[1],
                             },
                         }
-                        self._port.send_json(work)
+                        try:
+                            self._port.send_json(work)
+                        except EOFError:
+                            # The work item is lost, but the next read notices
+                            # the disconnect and reconnects if possible.
+                            if self._resume_token is None:
+                                raise
 
         except EOFError:
             finish_reason = "disconnected from permuter@home"
@@ -238,13 +322,23 @@ def start_client(
     perm_index: int,
     feedback_queue: "Queue[Feedback]",
     priority: float,
+    required_tags: List[str],
+    docker_image: Optional[str],
 ) -> "Tuple[threading.Thread, Queue[Task], Tuple[int, int, float]]":
-    port.send_json(
-        {
-            "method": "connect_client",
-            "priority": priority,
+    request: dict = {
+        "method": "connect_client",
+        "priority": priority,
+        "previous_best": True,
+        "resumable": True,
+    }
+    seed_count = permuter.network_seed_count()
+    if seed_count is not None and seed_count < 2 ** 64:
+        request["seeds"] = {
+            "count": seed_count,
+            "repeat": True,
+            "rng_seed": random.randrange(2 ** 63),
         }
-    )
+    port.send_json(request)
     obj = port.receive_json()
     if "error" in obj:
         err = json_prop(obj, "error", str)
@@ -253,11 +347,12 @@ def start_client(
     num_servers = json_prop(obj, "servers", int)
     num_clients = json_prop(obj, "clients", int)
     num_cores = json_prop(obj, "cores", float)
-    permuter_data = make_portable_permuter(permuter)
+    permuter_data = make_portable_permuter(permuter, required_tags, docker_image)
     task_queue: "Queue[Task]" = Queue()
 
     conn = Connection(
         port,
+        request,
         permuter_data,
         perm_index,
         task_queue,
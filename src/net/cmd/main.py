@@ -5,6 +5,8 @@ from ..core import ServerError, enable_debug_mode
 from .run_server import RunServerCommand
 from .setup import SetupCommand
 from .ping import PingCommand
+from .revoke import RevokeCommand
+from .set_roles import SetRolesCommand
 from .vouch import VouchCommand
 
 
@@ -27,7 +29,9 @@ def main() -> None:
 
     commands = [
         PingCommand,
+        RevokeCommand,
         RunServerCommand,
+        SetRolesCommand,
         SetupCommand,
         VouchCommand,
     ]
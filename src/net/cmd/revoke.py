@@ -0,0 +1,40 @@
+from argparse import ArgumentParser, Namespace
+
+from ..core import connect, json_prop
+from .base import Command
+
+
+class RevokeCommand(Command):
+    command = "revoke"
+    help = "Revoke someone's access to the central server."
+
+    @staticmethod
+    def add_arguments(parser: ArgumentParser) -> None:
+        parser.add_argument(
+            "who",
+            help="Name or hex id of the user to revoke. Must be someone you "
+            "have vouched for, directly or indirectly.",
+        )
+        parser.add_argument(
+            "--cascade",
+            action="store_true",
+            help="Also revoke everyone they have vouched for.",
+        )
+
+    @staticmethod
+    def run(args: Namespace) -> None:
+        run_revoke(args.who, args.cascade)
+
+
+def run_revoke(who: str, cascade: bool) -> None:
+    port = connect()
+    port.send_json(
+        {
+            "method": "revoke",
+            "who": who,
+            "cascade": cascade,
+        }
+    )
+    msg = port.receive_json()
+    revoked = json_prop(msg, "revoked", list)
+    print("Revoked:", ", ".join(revoked))
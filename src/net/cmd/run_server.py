@@ -78,6 +78,24 @@ class RunServerCommand(Command):
             help="""Only accept jobs from clients who pass --priority with a number
                 higher or equal to this value. (default: %(default)s)""",
         )
+        parser.add_argument(
+            "--tag",
+            dest="tags",
+            metavar="TAG",
+            action="append",
+            default=[],
+            help="""Advertise a capability tag, e.g. a toolchain or architecture
+                that this machine can run. Jobs that require tags are only sent
+                to servers that have all of them. Can be passed multiple times.""",
+        )
+        parser.add_argument(
+            "--docker-image",
+            dest="docker_image",
+            metavar="NAME",
+            help="""Run jobs in the docker image with the given name, as
+                configured on the central server, instead of the default one.
+                Only jobs that ask for this image are sent to the server.""",
+        )
 
     @staticmethod
     def run(args: Namespace) -> None:
@@ -85,6 +103,8 @@ class RunServerCommand(Command):
             num_cores=args.num_cores,
             max_memory_gb=args.max_memory_gb,
             min_priority=args.min_priority,
+            tags=args.tags,
+            docker_image=args.docker_image,
         )
 
         server_main(options, args.systray)
@@ -553,7 +573,10 @@ def main_loop(
                 if activity.message:
                     print("Server error:", activity.message)
                 print("disconnected from permuter@home")
-                server.stop()
+                if activity.graceful:
+                    server.suspend()
+                else:
+                    server.stop()
                 reconnector.mark_stop()
                 systray.server_failed(activity.graceful, activity.message)
 
@@ -0,0 +1,39 @@
+from argparse import ArgumentParser, Namespace
+
+from ..core import connect, json_prop
+from .base import Command
+
+
+class SetRolesCommand(Command):
+    command = "set-roles"
+    help = "Change someone's roles on the central server. Admins only."
+
+    @staticmethod
+    def add_arguments(parser: ArgumentParser) -> None:
+        parser.add_argument(
+            "who",
+            help="Name or hex id of the user.",
+        )
+        parser.add_argument(
+            "roles",
+            help="Comma-separated list of roles (vouch, serve, submit, admin). "
+            "Pass an empty string to disable the user.",
+        )
+
+    @staticmethod
+    def run(args: Namespace) -> None:
+        run_set_roles(args.who, args.roles)
+
+
+def run_set_roles(who: str, roles: str) -> None:
+    port = connect()
+    port.send_json(
+        {
+            "method": "set_roles",
+            "who": who,
+            "roles": [role.strip() for role in roles.split(",") if role.strip()],
+        }
+    )
+    msg = port.receive_json()
+    new_roles = json_prop(msg, "roles", list)
+    print("Roles:", ", ".join(new_roles) or "(none)")
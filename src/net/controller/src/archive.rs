@@ -0,0 +1,250 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use flate2::read::ZlibDecoder;
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::hash::sha256;
+use tempfile::NamedTempFile;
+
+use crate::db::UserId;
+use crate::util::SimpleResult;
+
+const INDEX_FILE: &str = "index.jsonl";
+const BLOB_DIR: &str = "blobs";
+/// Largest uncompressed source accepted, so that a small compressed blob
+/// can't expand to fill memory.
+const MAX_SOURCE_BYTES: u64 = 64 << 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    /// a source that matched
+    Match,
+    /// the best-scoring source so far for a function and base hash
+    Best,
+}
+
+/// A result found for a client.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ResultInfo {
+    /// Unix timestamp
+    pub time: i64,
+    pub fn_name: String,
+    pub base_hash: Option<String>,
+    pub score: i64,
+    pub hash: Option<String>,
+    pub client: UserId,
+    pub server: UserId,
+}
+
+/// An entry in the archive index.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Entry {
+    pub kind: Kind,
+    /// SHA-256 of the uncompressed source, naming the file it's stored in
+    pub blob: String,
+    #[serde(flatten)]
+    pub info: ResultInfo,
+}
+
+type BestKey = (String, Option<String>);
+
+/// The live entries of the archive: every match, and the latest best for
+/// each function and base hash.
+#[derive(Default)]
+struct Index {
+    matches: HashSet<(String, String)>,
+    best: HashMap<BestKey, Entry>,
+    blob_refs: HashMap<String, usize>,
+}
+
+impl Index {
+    /// Add an entry. Returns the blob of the replaced best entry, if it is no
+    /// longer referenced.
+    fn add(&mut self, entry: Entry) -> Option<String> {
+        *self.blob_refs.entry(entry.blob.clone()).or_default() += 1;
+        let old = match entry.kind {
+            Kind::Match => {
+                self.matches
+                    .insert((entry.info.fn_name.clone(), entry.blob.clone()));
+                None
+            }
+            Kind::Best => {
+                let key = (entry.info.fn_name.clone(), entry.info.base_hash.clone());
+                self.best.insert(key, entry)
+            }
+        };
+        let old_blob = old?.blob;
+        let refs = self.blob_refs.get_mut(&old_blob).unwrap();
+        *refs -= 1;
+        if *refs == 0 {
+            self.blob_refs.remove(&old_blob);
+            Some(old_blob)
+        } else {
+            None
+        }
+    }
+
+    /// Entries that a new result with the given source would add.
+    fn new_entries(&self, info: &ResultInfo, blob: &str) -> Vec<Entry> {
+        let mut ret = Vec::new();
+        let entry = |kind| Entry {
+            kind,
+            blob: blob.to_string(),
+            info: info.clone(),
+        };
+        let match_key = (info.fn_name.clone(), blob.to_string());
+        if info.score == 0 && !self.matches.contains(&match_key) {
+            ret.push(entry(Kind::Match));
+        }
+        let best_key = (info.fn_name.clone(), info.base_hash.clone());
+        if self
+            .best
+            .get(&best_key)
+            .is_none_or(|best| info.score < best.info.score)
+        {
+            ret.push(entry(Kind::Best));
+        }
+        ret
+    }
+}
+
+/// On-disk archive of matched and best-so-far sources. Sources are stored
+/// deduplicated by content hash under blobs/, and index.jsonl is an
+/// append-only log of entries pointing to them.
+pub struct Archive {
+    dir: PathBuf,
+    index: Mutex<Index>,
+}
+
+pub fn blob_path(dir: &Path, blob: &str) -> PathBuf {
+    dir.join(BLOB_DIR).join(blob)
+}
+
+/// Read all entries from an archive's index, oldest first, including ones
+/// that have since been superseded.
+pub fn read_entries(dir: &Path) -> SimpleResult<Vec<Entry>> {
+    let file = match File::open(dir.join(INDEX_FILE)) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => Err(e)?,
+    };
+    let mut entries = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if !line.is_empty() {
+            entries.push(serde_json::from_str(&line)?);
+        }
+    }
+    Ok(entries)
+}
+
+/// The live entries of an archive: every match, and the current best for
+/// each function and base hash.
+pub fn live_entries(dir: &Path) -> SimpleResult<Vec<Entry>> {
+    let entries = read_entries(dir)?;
+    let mut index = Index::default();
+    for entry in entries.iter().cloned() {
+        index.add(entry);
+    }
+    let mut seen_matches = HashSet::new();
+    let mut live: Vec<Entry> = entries
+        .into_iter()
+        .filter(|entry| {
+            entry.kind == Kind::Match
+                && seen_matches.insert((entry.info.fn_name.clone(), entry.blob.clone()))
+        })
+        .chain(index.best.into_values())
+        .collect();
+    live.sort_by_key(|entry| entry.info.time);
+    Ok(live)
+}
+
+pub fn decompress(compressed_source: &[u8]) -> SimpleResult<Vec<u8>> {
+    let mut source = Vec::new();
+    ZlibDecoder::new(compressed_source)
+        .take(MAX_SOURCE_BYTES + 1)
+        .read_to_end(&mut source)?;
+    if source.len() as u64 > MAX_SOURCE_BYTES {
+        Err("source is too large")?;
+    }
+    Ok(source)
+}
+
+impl Archive {
+    pub fn open(dir: &str) -> SimpleResult<Archive> {
+        let dir = PathBuf::from(dir);
+        fs::create_dir_all(dir.join(BLOB_DIR))?;
+        let mut index = Index::default();
+        for entry in read_entries(&dir)? {
+            index.add(entry);
+        }
+        Ok(Archive {
+            dir,
+            index: Mutex::new(index),
+        })
+    }
+
+    /// The best archived result for a function and base hash, with its
+    /// compressed source.
+    pub fn best(
+        &self,
+        fn_name: &str,
+        base_hash: Option<&str>,
+    ) -> SimpleResult<Option<(Entry, Vec<u8>)>> {
+        let key = (fn_name.to_string(), base_hash.map(str::to_string));
+        // Read the blob with the index locked, so that a concurrent store
+        // can't remove it in the meantime.
+        let index = self.index.lock().unwrap();
+        let entry = match index.best.get(&key) {
+            Some(entry) => entry.clone(),
+            None => return Ok(None),
+        };
+        let compressed_source = fs::read(blob_path(&self.dir, &entry.blob))?;
+        Ok(Some((entry, compressed_source)))
+    }
+
+    /// Archive a result, if it's a new match or better than the best so far.
+    pub fn store(&self, info: &ResultInfo, compressed_source: &[u8]) -> SimpleResult<()> {
+        let source = decompress(compressed_source)?;
+        let blob = hex::encode(sha256::hash(&source));
+
+        let mut index = self.index.lock().unwrap();
+        let entries = index.new_entries(info, &blob);
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        // Blobs are written before the index entries that refer to them, so
+        // that a crash can't leave dangling entries.
+        let path = blob_path(&self.dir, &blob);
+        if !path.exists() {
+            let mut tempf = NamedTempFile::new_in(self.dir.join(BLOB_DIR))?;
+            tempf.write_all(compressed_source)?;
+            tempf.as_file().sync_all()?;
+            tempf.persist(&path)?;
+        }
+
+        let mut index_file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(INDEX_FILE))?;
+        let mut lines = String::new();
+        for entry in &entries {
+            lines += &serde_json::to_string(entry)?;
+            lines.push('\n');
+        }
+        index_file.write_all(lines.as_bytes())?;
+        index_file.sync_data()?;
+
+        for entry in entries {
+            if let Some(old_blob) = index.add(entry) {
+                let _ = fs::remove_file(blob_path(&self.dir, &old_blob));
+            }
+        }
+        Ok(())
+    }
+}
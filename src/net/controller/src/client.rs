@@ -1,29 +1,61 @@
-use std::collections::VecDeque;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use tokio::sync::mpsc;
+use tokio::time;
 
-use crate::db::UserId;
+use crate::archive::Entry;
+use crate::db::{Limits, UserId};
 use crate::flimsy_semaphore::FlimsySemaphore;
+use crate::logging;
 use crate::port::{ReadPort, WritePort};
+use crate::seeds::{SeedGenerator, SeedSpec};
 use crate::stats;
-use crate::util::SimpleResult;
+use crate::util::{self, SimpleResult};
 use crate::{
-    current_load, Permuter, PermuterData, PermuterId, PermuterResult, PermuterWork, ServerUpdate,
-    State,
+    current_load, is_disconnect, MutableState, Permuter, PermuterData, PermuterId, PermuterResult,
+    PermuterWork, ServerUpdate, Session, State,
 };
 
 const MIN_PERMUTER_VERSION: u32 = 1;
 
-const CLIENT_MAX_QUEUES_SIZE: usize = 100;
+pub(crate) const CLIENT_MAX_QUEUES_SIZE: usize = 100;
 const MIN_PRIORITY: f64 = 0.001;
 const MAX_PRIORITY: f64 = 10.0;
 
+/// Error for a client whose attachment was taken away, e.g. because its
+/// user's access was revoked.
+const DETACHED: &str = "detached from permuter";
+
 #[derive(Debug, Deserialize)]
 pub(crate) struct ConnectClientData {
     priority: f64,
+    /// whether to send the best archived result for the function, if any
+    #[serde(default)]
+    previous_best: bool,
+    /// whether to hand out a resume token for the session
+    #[serde(default)]
+    resumable: bool,
+    /// resume token of an earlier session to reattach to
+    #[serde(default)]
+    resume: Option<String>,
+    /// seeds for the controller to generate work from, instead of the client
+    /// sending each work item
+    #[serde(default)]
+    seeds: Option<SeedSpec>,
+}
+
+/// A client whose connection dropped, with its permuter kept registered for
+/// a grace period so that it can resume.
+pub(crate) struct SuspendedClient {
+    perm_id: PermuterId,
+    attachment_id: u64,
+    client_id: UserId,
+    /// results that arrived since the disconnect, which will be sent on resume
+    result_rx: mpsc::UnboundedReceiver<PermuterResult>,
+    since: Instant,
 }
 
 #[derive(Deserialize)]
@@ -39,9 +71,83 @@ struct PermuterResultMessage<'a> {
     update: &'a ServerUpdate,
 }
 
+fn user_limits(state: &State, who_id: &UserId) -> Limits {
+    state.db.read(|db| match db.users().get(who_id) {
+        Some(user) => user.limits.or(&state.default_limits),
+        None => state.default_limits.clone(),
+    })
+}
+
+fn check_cpu_budget(state: &State, who_id: &UserId, limits: &Limits) -> Result<(), String> {
+    if let Some(max_hours) = limits.daily_cpu_hours {
+        let used_hours = state.db.read(|db| {
+            db.users()
+                .get(who_id)
+                .map_or(0.0, |user| user.cpu_usage.today_hours())
+        });
+        if used_hours >= max_hours {
+            return Err(format!(
+                "Daily CPU budget of {} hours used up, try again tomorrow",
+                max_hours
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn check_max_permuters(m: &MutableState, who_id: &UserId, limits: &Limits) -> Result<(), String> {
+    if let Some(max_permuters) = limits.max_permuters {
+        if m.attached_permuters(who_id) >= max_permuters {
+            return Err(format!(
+                "Too many concurrent permuters (limit is {})",
+                max_permuters
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn check_limits(
+    state: &State,
+    who_id: &UserId,
+    priority: f64,
+    limits: &Limits,
+) -> Result<(), String> {
+    if let Some(max_priority) = limits.max_priority {
+        if priority > max_priority {
+            return Err(format!(
+                "Priority {} is above your limit of {}",
+                priority, max_priority
+            ));
+        }
+    }
+    check_cpu_budget(state, who_id, limits)?;
+    check_max_permuters(&state.m.lock().unwrap(), who_id, limits)
+}
+
+/// The best result from earlier sessions on the same function and base, if
+/// the client asked for it.
+fn previous_best(
+    state: &State,
+    data: &ConnectClientData,
+    permuter_data: &PermuterData,
+) -> Option<(Entry, Vec<u8>)> {
+    let archive = state.archive.as_ref().filter(|_| data.previous_best)?;
+    let base_hash = permuter_data.base_hash();
+    let r = tokio::task::block_in_place(|| archive.best(&permuter_data.fn_name, base_hash));
+    r.unwrap_or_else(|e| {
+        logging::warn(
+            "failed to read archived result",
+            &[("error", e.to_string().into())],
+        );
+        None
+    })
+}
+
 async fn client_read(
     port: &mut ReadPort<'_>,
     perm_id: &PermuterId,
+    attachment_id: u64,
     semaphore: &FlimsySemaphore,
     state: &State,
 ) -> SimpleResult<()> {
@@ -55,25 +161,30 @@ async fn client_read(
         semaphore.acquire().await;
 
         let mut m = state.m.lock().unwrap();
-        let perm = m.permuters.get_mut(perm_id).unwrap();
-        if perm.work_queue.is_empty() {
+        let perm = m.permuters.get_mut(perm_id).ok_or(DETACHED)?;
+        if perm.work_queue_len() == 0 {
             state.new_work_notification.notify_waiters();
         }
-        perm.work_queue.push_back(work);
+        let client = perm.client_mut(attachment_id).ok_or(DETACHED)?;
+        client.work_queue.push_back(work);
     }
 }
 
 async fn client_write(
     port: &mut WritePort<'_>,
-    fn_name: &str,
     semaphore: &FlimsySemaphore,
     state: &State,
-    mut result_rx: mpsc::UnboundedReceiver<PermuterResult>,
+    result_rx: &mut mpsc::UnboundedReceiver<PermuterResult>,
     client_id: &UserId,
+    limits: &Limits,
 ) -> SimpleResult<()> {
     loop {
-        let res = result_rx.recv().await.unwrap();
+        let res = result_rx.recv().await.ok_or(DETACHED)?;
         semaphore.release();
+        if semaphore.available() == 1 {
+            // Work generation may have been held back by a full result queue.
+            state.new_work_notification.notify_waiters();
+        }
 
         match res {
             PermuterResult::NeedWork => {
@@ -82,7 +193,7 @@ async fn client_write(
                 }))
                 .await?;
             }
-            PermuterResult::Result(server_id, server_name, server_update) => {
+            PermuterResult::Result(server_name, server_update, _) => {
                 port.send_json(&PermuterResultMessage {
                     server: server_name,
                     update: &server_update,
@@ -90,66 +201,149 @@ async fn client_write(
                 .await?;
 
                 if let ServerUpdate::Result {
-                    compressed_source,
-                    ref more_props,
-                    ..
+                    compressed_source, ..
                 } = server_update
                 {
                     if let Some(ref data) = compressed_source {
                         port.send(data).await?;
                     }
 
-                    let score = more_props.get("score").and_then(|score| score.as_i64());
-                    let outcome = if compressed_source.is_none() {
-                        stats::Outcome::Unhelpful
-                    } else if matches!(score, Some(0)) {
-                        stats::Outcome::Matched
-                    } else {
-                        stats::Outcome::Improved
-                    };
-                    state
-                        .log_stats(stats::Record::WorkDone {
-                            server: server_id,
-                            client: client_id.clone(),
-                            fn_name: fn_name.to_string(),
-                            outcome,
-                        })
-                        .await?;
+                    if let Err(e) = check_cpu_budget(state, client_id, limits) {
+                        port.send_error(&e).await?;
+                        Err(e)?;
+                    }
                 }
             }
         }
     }
 }
 
-pub(crate) async fn handle_connect_client<'a>(
-    mut read_port: ReadPort<'a>,
-    mut write_port: WritePort<'a>,
-    who_id: UserId,
-    who_name: &str,
-    permuter_version: u32,
-    state: &State,
-    data: ConnectClientData,
-) -> SimpleResult<()> {
-    if permuter_version < MIN_PERMUTER_VERSION {
-        Err("Permuter version too old!")?;
+fn take_suspended(state: &State, token: &str, who_id: &UserId) -> Option<SuspendedClient> {
+    let mut m = state.m.lock().unwrap();
+    if m.suspended_clients.get(token)?.client_id != *who_id {
+        return None;
     }
+    m.suspended_clients.remove(token)
+}
 
-    if !(MIN_PRIORITY <= data.priority && data.priority <= MAX_PRIORITY) {
-        Err("Priority out of range")?;
-    }
+/// Keep the permuter of a disconnected client registered until the grace
+/// period runs out, unless the client resumes before then.
+fn suspend(state: &'static State, token: String, suspended: SuspendedClient, grace: Duration) {
+    let perm_id = suspended.perm_id;
+    let since = suspended.since;
+    state
+        .m
+        .lock()
+        .unwrap()
+        .suspended_clients
+        .insert(token.clone(), suspended);
+    let context = logging::Context {
+        permuter: Some(perm_id),
+        ..Default::default()
+    };
+    tokio::spawn(logging::scope(context, async move {
+        time::sleep(grace).await;
+        let mut m = state.m.lock().unwrap();
+        // The client may have resumed, and perhaps been suspended again.
+        if m.suspended_clients
+            .get(&token)
+            .is_some_and(|suspended| suspended.since == since)
+        {
+            let suspended = m.suspended_clients.remove(&token).unwrap();
+            m.detach_client(perm_id, suspended.attachment_id);
+            drop(m);
+            state.new_work_notification.notify_waiters();
+            logging::info("client resume expired", &[]);
+        }
+    }));
+}
 
-    let load = current_load(state, Some(data.priority));
-    write_port.send_json(&load).await?;
+/// Drop the suspended sessions of a user, detaching them from their
+/// permuters.
+pub(crate) fn drop_suspended(m: &mut MutableState, user_id: &UserId) {
+    let mut detached = Vec::new();
+    m.suspended_clients.retain(|_, suspended| {
+        let keep = suspended.client_id != *user_id;
+        if !keep {
+            detached.push((suspended.perm_id, suspended.attachment_id));
+        }
+        keep
+    });
+    for (perm_id, attachment_id) in detached {
+        m.detach_client(perm_id, attachment_id);
+    }
+}
 
+/// Receive the permuter from the client and register it, or attach the
+/// client to an identical one that is already running. Returns its id, the
+/// client's attachment id, the resume token handed out, if any, and the
+/// client's result channel.
+#[allow(clippy::too_many_arguments)]
+async fn start_permuter(
+    read_port: &mut ReadPort<'_>,
+    write_port: &mut WritePort<'_>,
+    who_id: &UserId,
+    who_name: &str,
+    permuter_version: u32,
+    state: &State,
+    data: &ConnectClientData,
+    limits: &Limits,
+) -> SimpleResult<(
+    PermuterId,
+    u64,
+    Option<String>,
+    mpsc::UnboundedReceiver<PermuterResult>,
+)> {
     let permuter_data = read_port.recv().await?;
     let mut permuter_data: PermuterData = serde_json::from_slice(&permuter_data)?;
     permuter_data.compressed_source = read_port.recv().await?;
     permuter_data.compressed_target_o_bin = read_port.recv().await?;
-    write_port.send_json(&json!({})).await?;
+    permuter_data.hash_blobs();
+    if let Err(e) = state
+        .docker_images
+        .get(permuter_data.docker_image(), permuter_version)
+    {
+        write_port.send_error(&e).await?;
+        Err(e)?;
+    }
 
-    eprintln!(
-        "[{}] start client ({}, {})",
-        &who_name, &permuter_data.fn_name, data.priority
+    let token = (data.resumable && state.client_resume_grace.is_some()).then(util::resume_token);
+    let previous = previous_best(state, data, &permuter_data);
+    let mut reply = json!({});
+    if let Some((ref entry, _)) = previous {
+        reply["previous_best"] = json!({
+            "score": entry.info.score,
+            "hash": entry.info.hash,
+            "has_source": true,
+        });
+    }
+    if let Some(ref token) = token {
+        reply["resume_token"] = token.as_str().into();
+    }
+    if data.seeds.is_some() {
+        reply["generated_seeds"] = true.into();
+    }
+    if !permuter_data.required_tags.is_empty() || permuter_data.docker_image.is_some() {
+        // Let the client know if nobody can currently run the job.
+        let m = state.m.lock().unwrap();
+        let capable = m
+            .servers
+            .values()
+            .filter(|server| permuter_data.can_run_on(&server.tags, &server.docker_images))
+            .count();
+        reply["capable_servers"] = capable.into();
+    }
+    write_port.send_json(&reply).await?;
+    if let Some((_, compressed_source)) = previous {
+        write_port.send(&compressed_source).await?;
+    }
+
+    logging::info(
+        "start client",
+        &[
+            ("fn_name", permuter_data.fn_name.clone().into()),
+            ("priority", data.priority.into()),
+        ],
     );
 
     state
@@ -159,47 +353,195 @@ pub(crate) async fn handle_connect_client<'a>(
         })
         .await?;
 
-    let energy_add = 1.0 / data.priority;
-    let fn_name = permuter_data.fn_name.clone();
-
+    let perm = Permuter::new(
+        Arc::new(permuter_data),
+        who_id.clone(),
+        who_name.to_string(),
+        data.priority,
+    );
     let (result_tx, result_rx) = mpsc::unbounded_channel();
-    let semaphore = Arc::new(FlimsySemaphore::new(CLIENT_MAX_QUEUES_SIZE));
+    let seeds = data.seeds.as_ref().map(SeedGenerator::new);
 
-    let perm_id = {
+    let attached = {
         let mut m = state.m.lock().unwrap();
-        let id = m.next_permuter_id;
-        m.next_permuter_id += 1;
-        m.permuters.insert(
-            id,
-            Permuter {
-                data: permuter_data.into(),
-                client_id: who_id.clone(),
-                client_name: who_name.to_string(),
-                work_queue: VecDeque::new(),
-                result_tx: result_tx.clone(),
-                semaphore: semaphore.clone(),
-                priority: data.priority,
-                energy_add,
-            },
-        );
-        state.new_work_notification.notify_waiters();
-        id
+        // Check again, in case other connections have started meanwhile.
+        check_max_permuters(&m, who_id, limits).map(|()| {
+            let id = match m.job_keys.get(&perm.job_key) {
+                Some(&id) => {
+                    logging::info(
+                        "join shared job",
+                        &[("owner", m.permuters[&id].client_name.clone().into())],
+                    );
+                    id
+                }
+                None => {
+                    state.new_work_notification.notify_waiters();
+                    m.add_permuter(perm)
+                }
+            };
+            let attachment_id = m.attach_client(
+                id,
+                who_id.clone(),
+                who_name.to_string(),
+                data.priority,
+                result_tx,
+                seeds,
+            );
+            (id, attachment_id)
+        })
+    };
+    match attached {
+        Ok((id, attachment_id)) => Ok((id, attachment_id, token, result_rx)),
+        Err(e) => {
+            write_port.send_error(&e).await?;
+            Err(e)?
+        }
+    }
+}
+
+/// Check the client against its user's limits, then tell it the current
+/// load and, if it asked to resume, whether it did.
+async fn accept_client(
+    write_port: &mut WritePort<'_>,
+    state: &State,
+    who_id: &UserId,
+    data: &ConnectClientData,
+    resuming: bool,
+) -> SimpleResult<Limits> {
+    let limits = user_limits(state, who_id);
+    // A resumed permuter already counts towards the concurrency limit.
+    let r = if resuming {
+        check_cpu_budget(state, who_id, &limits)
+    } else {
+        check_limits(state, who_id, data.priority, &limits)
+    };
+    if let Err(e) = r {
+        write_port.send_error(&e).await?;
+        Err(e)?;
+    }
+
+    let load = current_load(state, Some(data.priority));
+    write_port.send_json(&load).await?;
+    if data.resume.is_some() {
+        write_port
+            .send_json(&json!({ "resumed": resuming }))
+            .await?;
+    }
+    Ok(limits)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn handle_connect_client<'a>(
+    mut read_port: ReadPort<'a>,
+    mut write_port: WritePort<'a>,
+    who_id: UserId,
+    who_name: &str,
+    permuter_version: u32,
+    state: &'static State,
+    session: &Session<'_>,
+    data: ConnectClientData,
+) -> SimpleResult<()> {
+    if permuter_version < MIN_PERMUTER_VERSION {
+        Err("Permuter version too old!")?;
+    }
+
+    if !(MIN_PRIORITY <= data.priority && data.priority <= MAX_PRIORITY) {
+        Err("Priority out of range")?;
+    }
+
+    if data.seeds.as_ref().is_some_and(|seeds| seeds.count == 0) {
+        Err("Seed count must be positive")?;
+    }
+
+    let suspended = data
+        .resume
+        .as_deref()
+        .and_then(|token| take_suspended(state, token, &who_id));
+
+    let resuming = suspended.is_some();
+    let limits = match accept_client(&mut write_port, state, &who_id, &data, resuming).await {
+        Ok(limits) => limits,
+        Err(e) => {
+            // Give the client a new grace period in which to try again.
+            if let (Some(mut suspended), Some(grace)) = (suspended, state.client_resume_grace) {
+                suspended.since = Instant::now();
+                suspend(state, data.resume.unwrap(), suspended, grace);
+            }
+            return Err(e);
+        }
+    };
+
+    let (perm_id, attachment_id, token, mut result_rx) = match suspended {
+        Some(suspended) => {
+            logging::info("resume client", &[("permuter", suspended.perm_id.into())]);
+            (
+                suspended.perm_id,
+                suspended.attachment_id,
+                data.resume.clone(),
+                suspended.result_rx,
+            )
+        }
+        None => {
+            start_permuter(
+                &mut read_port,
+                &mut write_port,
+                &who_id,
+                who_name,
+                permuter_version,
+                state,
+                &data,
+                &limits,
+            )
+            .await?
+        }
+    };
+    logging::update_context(|c| c.permuter = Some(perm_id));
+
+    let semaphore = {
+        let mut m = state.m.lock().unwrap();
+        m.permuters
+            .get_mut(&perm_id)
+            .and_then(|perm| perm.client_mut(attachment_id))
+            .ok_or(DETACHED)?
+            .semaphore
+            .clone()
     };
 
     let r = tokio::try_join!(
-        client_read(&mut read_port, &perm_id, &semaphore, state),
+        client_read(&mut read_port, &perm_id, attachment_id, &semaphore, state),
         client_write(
             &mut write_port,
-            &fn_name,
             &semaphore,
             state,
-            result_rx,
-            &who_id
-        )
+            &mut result_rx,
+            &who_id,
+            &limits
+        ),
+        session.kicked()
     );
 
-    state.m.lock().unwrap().permuters.remove(&perm_id);
-    state.new_work_notification.notify_waiters();
+    // If the connection dropped, give the client a chance to resume.
+    match (token, state.client_resume_grace) {
+        (Some(token), Some(grace)) if matches!(&r, Err(e) if is_disconnect(&**e)) => {
+            logging::info("suspend client", &[("grace_secs", grace.as_secs().into())]);
+            let suspended = SuspendedClient {
+                perm_id,
+                attachment_id,
+                client_id: who_id,
+                result_rx,
+                since: Instant::now(),
+            };
+            suspend(state, token, suspended, grace);
+        }
+        _ => {
+            state
+                .m
+                .lock()
+                .unwrap()
+                .detach_client(perm_id, attachment_id);
+            state.new_work_notification.notify_waiters();
+        }
+    }
     r?;
     Ok(())
 }
@@ -0,0 +1,60 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use tokio::time;
+
+use crate::State;
+
+const REFRESH_INTERVAL: time::Duration = time::Duration::from_secs(60);
+
+/// Settings for tit-for-tat scheduling, where users earn credit by running
+/// servers that do work for others, and credit boosts the scheduling weight
+/// of their own clients. The boost only matters when servers are contended,
+/// so users without credit still get served.
+#[derive(Clone, Deserialize)]
+pub(crate) struct CreditConfig {
+    /// time for credit to decay to half its value
+    #[serde(default = "default_half_life_hours")]
+    pub half_life_hours: f64,
+    /// the largest boost to a user's priority, as an added multiple of it
+    #[serde(default = "default_max_boost")]
+    max_boost: f64,
+    /// amount of credit (in CPU hours) that gives half of the max boost
+    #[serde(default = "default_scale_hours")]
+    scale_hours: f64,
+}
+
+fn default_half_life_hours() -> f64 {
+    24.0 * 7.0
+}
+
+fn default_max_boost() -> f64 {
+    4.0
+}
+
+fn default_scale_hours() -> f64 {
+    100.0
+}
+
+impl CreditConfig {
+    fn boost(&self, credit_hours: f64) -> f64 {
+        1.0 + self.max_boost * credit_hours / (credit_hours + self.scale_hours)
+    }
+}
+
+/// Periodically recompute the priority boosts of all users from their credit.
+pub(crate) async fn refresh_boosts_loop(state: &State, config: &CreditConfig) {
+    loop {
+        let boosts: HashMap<_, _> = state.db.read(|db| {
+            db.users()
+                .iter()
+                .map(|(id, user)| {
+                    let credit = user.credit.value(config.half_life_hours);
+                    (id.clone(), config.boost(credit))
+                })
+                .collect()
+        });
+        state.m.lock().unwrap().credit_boosts = boosts;
+        time::sleep(REFRESH_INTERVAL).await;
+    }
+}
@@ -1,22 +1,26 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::convert::TryInto;
+use std::fmt;
+use std::str::FromStr;
 
+use chrono::Utc;
 use hex::FromHex;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
 use serde_tuple::{Deserialize_tuple, Serialize_tuple};
 use sodiumoxide::crypto::sign;
 
-#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+#[derive(Clone, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
 pub struct ByteString<const SIZE: usize>([u8; SIZE]);
 
 impl<const SIZE: usize> ByteString<SIZE> {
-    fn to_hex(&self) -> String {
-        hex::encode(&self.0)
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0)
     }
 
-    fn from_hex(string: &str) -> Result<ByteString<SIZE>, &'static str> {
+    pub fn from_hex(string: &str) -> Result<ByteString<SIZE>, &'static str> {
         Ok(ByteString(
-            Vec::from_hex(&string)
+            Vec::from_hex(string)
                 .map_err(|_| "not a valid hex string")?
                 .try_into()
                 .map_err(|_| "byte string has wrong size")?,
@@ -62,7 +66,7 @@ impl<const SIZE: usize> ByteString<SIZE> {
     }
 }
 
-#[derive(Debug, Deserialize_tuple, Serialize_tuple)]
+#[derive(Clone, Debug, Default, Deserialize_tuple, Serialize_tuple)]
 pub struct Stats {
     pub iterations: u64,
     pub improvements: u64,
@@ -70,36 +74,700 @@ pub struct Stats {
     pub functions: u64,
 }
 
-impl Default for Stats {
-    fn default() -> Stats {
-        Stats {
-            iterations: 0,
-            improvements: 0,
-            matches: 0,
-            functions: 0,
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// May give new users access.
+    Vouch,
+    /// May run a permuter server.
+    Serve,
+    /// May submit jobs as a client.
+    Submit,
+    /// Implies all other roles, and may revoke any user.
+    Admin,
+}
+
+impl Role {
+    pub const ALL: [Role; 4] = [Role::Vouch, Role::Serve, Role::Submit, Role::Admin];
+
+    fn name(self) -> &'static str {
+        match self {
+            Role::Vouch => "vouch",
+            Role::Serve => "serve",
+            Role::Submit => "submit",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Role, String> {
+        Role::ALL
+            .iter()
+            .copied()
+            .find(|role| role.name() == s)
+            .ok_or_else(|| format!("unknown role {}", s))
+    }
+}
+
+pub type Roles = BTreeSet<Role>;
+
+pub fn has_role(roles: &Roles, role: Role) -> bool {
+    roles.contains(&role) || roles.contains(&Role::Admin)
+}
+
+/// Per-user limits on client usage. Unset limits fall back to the defaults
+/// from the config, and if unset there too, are unlimited.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Limits {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_permuters: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_priority: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub daily_cpu_hours: Option<f64>,
+}
+
+impl Limits {
+    pub fn or(&self, defaults: &Limits) -> Limits {
+        Limits {
+            max_permuters: self.max_permuters.or(defaults.max_permuters),
+            max_priority: self.max_priority.or(defaults.max_priority),
+            daily_cpu_hours: self.daily_cpu_hours.or(defaults.daily_cpu_hours),
         }
     }
 }
 
+fn current_day() -> i64 {
+    Utc::now().timestamp().div_euclid(24 * 60 * 60)
+}
+
+/// Server CPU time spent on a user's jobs during the current (UTC) day.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct CpuUsage {
+    day: i64,
+    time_us: f64,
+}
+
+impl CpuUsage {
+    pub fn add(&mut self, time_us: f64) {
+        let day = current_day();
+        if self.day != day {
+            self.day = day;
+            self.time_us = 0.0;
+        }
+        self.time_us += time_us;
+    }
+
+    pub fn today_hours(&self) -> f64 {
+        if self.day != current_day() {
+            return 0.0;
+        }
+        self.time_us / (60.0 * 60.0 * 1_000_000.0)
+    }
+}
+
+/// Credit earned by donating server time to other users' jobs, in CPU hours.
+/// Decays exponentially over time.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Credit {
+    hours: f64,
+    updated: i64,
+}
+
+impl Credit {
+    pub fn value(&self, half_life_hours: f64) -> f64 {
+        let elapsed_hours = (Utc::now().timestamp() - self.updated).max(0) as f64 / 3600.0;
+        self.hours * 0.5f64.powf(elapsed_hours / half_life_hours)
+    }
+
+    pub fn add(&mut self, hours: f64, half_life_hours: f64) {
+        self.hours = self.value(half_life_hours) + hours;
+        self.updated = Utc::now().timestamp();
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct User {
     pub trusted_by: Option<UserId>,
     pub name: String,
+    pub roles: Roles,
+    #[serde(default)]
+    pub limits: Limits,
+    #[serde(default)]
+    pub cpu_usage: CpuUsage,
+    #[serde(default)]
+    pub credit: Credit,
     pub client_stats: Stats,
     pub server_stats: Stats,
 }
 
+/// The version of the database format written by this version of pahserver.
+pub const SCHEMA_VERSION: u64 = 3;
+
+/// A step that brings a database in its JSON form from one schema version to
+/// the next, describing each change it makes.
+struct Migration {
+    description: &'static str,
+    run: fn(&mut Map<String, Value>, &mut Vec<String>),
+}
+
+/// Migrations by the version they start from.
+const MIGRATIONS: [Migration; SCHEMA_VERSION as usize] = [
+    Migration {
+        description: "add roles to users",
+        run: migrate_add_roles,
+    },
+    Migration {
+        description: "add time-bucketed stats",
+        run: migrate_add_rollups,
+    },
+    Migration {
+        description: "add discovery log",
+        run: migrate_add_discoveries,
+    },
+];
+
+fn users_mut<'a>(
+    db: &'a mut Map<String, Value>,
+) -> impl Iterator<Item = (&'a String, &'a mut Value)> + 'a {
+    db.get_mut("users")
+        .and_then(|u| u.as_object_mut())
+        .into_iter()
+        .flat_map(|users| users.iter_mut())
+}
+
+fn migrate_add_roles(db: &mut Map<String, Value>, changes: &mut Vec<String>) {
+    for (id, user) in users_mut(db) {
+        // Before roles were introduced, everyone could do everything. Keep it
+        // that way, and make the root user an admin.
+        if user.get("roles").is_none() {
+            let mut roles = vec![Role::Vouch, Role::Serve, Role::Submit];
+            if user.get("trusted_by").is_none_or(|t| t.is_null()) {
+                roles.push(Role::Admin);
+            }
+            let roles_list: Vec<String> = roles.iter().map(Role::to_string).collect();
+            changes.push(format!("user {}: roles {}", id, roles_list.join(", ")));
+            user["roles"] = serde_json::to_value(roles).unwrap();
+        }
+    }
+}
+
+fn migrate_add_rollups(db: &mut Map<String, Value>, _changes: &mut Vec<String>) {
+    db.entry("rollups")
+        .or_insert_with(|| Value::Array(Vec::new()));
+}
+
+fn migrate_add_discoveries(db: &mut Map<String, Value>, _changes: &mut Vec<String>) {
+    db.entry("discoveries")
+        .or_insert_with(|| Value::Array(Vec::new()));
+}
+
+/// What a migration did, for reporting.
+pub struct MigrationReport {
+    pub from_version: u64,
+    /// A list of steps taken, each with a list of the changes it made.
+    pub steps: Vec<(&'static str, Vec<String>)>,
+}
+
+impl MigrationReport {
+    pub fn is_noop(&self) -> bool {
+        self.from_version == SCHEMA_VERSION
+    }
+}
+
+/// Bring a database written by an older version of pahserver up to date, in
+/// its JSON form. Databases from before versioning count as version 0.
+pub fn migrate(db: &mut Value) -> Result<MigrationReport, String> {
+    let db = db.as_object_mut().ok_or("database is not a JSON object")?;
+    let from_version = match db.get("schema_version") {
+        None => 0,
+        Some(version) => version.as_u64().ok_or("invalid schema_version")?,
+    };
+    if from_version > SCHEMA_VERSION {
+        return Err(format!(
+            "database has schema version {}, but this version of pahserver only supports up to {}",
+            from_version, SCHEMA_VERSION
+        ));
+    }
+    let mut steps = Vec::new();
+    for migration in &MIGRATIONS[from_version as usize..] {
+        let mut changes = Vec::new();
+        (migration.run)(db, &mut changes);
+        steps.push((migration.description, changes));
+    }
+    db.insert("schema_version".into(), SCHEMA_VERSION.into());
+    Ok(MigrationReport {
+        from_version,
+        steps,
+    })
+}
+
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Granularity {
+    Hour,
+    Day,
+}
+
+impl Granularity {
+    pub const ALL: [Granularity; 2] = [Granularity::Hour, Granularity::Day];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Granularity::Hour => "hour",
+            Granularity::Day => "day",
+        }
+    }
+
+    pub fn secs(self) -> i64 {
+        match self {
+            Granularity::Hour => 60 * 60,
+            Granularity::Day => 24 * 60 * 60,
+        }
+    }
+
+    /// The start of the bucket that contains the given timestamp.
+    pub fn bucket(self, timestamp: i64) -> i64 {
+        timestamp - timestamp.rem_euclid(self.secs())
+    }
+}
+
+impl FromStr for Granularity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Granularity, String> {
+        Granularity::ALL
+            .iter()
+            .copied()
+            .find(|g| g.name() == s)
+            .ok_or_else(|| format!("unknown granularity {}", s))
+    }
+}
+
+/// What a stats rollup covers.
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub enum Scope {
+    Total,
+    Client(UserId),
+    Server(UserId),
+    Function(String),
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Scope::Total => write!(f, "total"),
+            Scope::Client(id) => write!(f, "client:{}", id.to_hex()),
+            Scope::Server(id) => write!(f, "server:{}", id.to_hex()),
+            Scope::Function(fn_name) => write!(f, "function:{}", fn_name),
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Scope, String> {
+        let user = |id: &str| UserId::from_hex(id).map_err(str::to_string);
+        match s.split_once(':') {
+            None if s == "total" => Ok(Scope::Total),
+            Some(("client", id)) => Ok(Scope::Client(user(id)?)),
+            Some(("server", id)) => Ok(Scope::Server(user(id)?)),
+            Some(("function", fn_name)) => Ok(Scope::Function(fn_name.to_string())),
+            _ => Err(format!("invalid stats scope {}", s)),
+        }
+    }
+}
+
+impl Serialize for Scope {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Scope {
+    fn deserialize<D>(deserializer: D) -> Result<Scope, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use serde::de::Error;
+        let string = String::deserialize(deserializer)?;
+        string.parse().map_err(Error::custom)
+    }
+}
+
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct RollupKey {
+    pub granularity: Granularity,
+    /// start of the time bucket, as a Unix timestamp
+    pub start: i64,
+    pub scope: Scope,
+}
+
+/// Stats for a single time bucket.
+#[derive(Clone, Debug, Default, Deserialize_tuple, Serialize_tuple)]
+pub struct BucketStats {
+    pub stats: Stats,
+    pub cpu_us: f64,
+}
+
+#[derive(Deserialize_tuple, Serialize_tuple)]
+struct RollupEntry {
+    granularity: Granularity,
+    start: i64,
+    scope: Scope,
+    stats: Stats,
+    cpu_us: f64,
+}
+
+/// Time-bucketed stats, stored as a list since the keys aren't strings.
+#[derive(Debug, Default)]
+pub struct Rollups(HashMap<RollupKey, BucketStats>);
+
+impl Serialize for Rollups {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_seq(self.0.iter().map(|(key, bucket)| RollupEntry {
+            granularity: key.granularity,
+            start: key.start,
+            scope: key.scope.clone(),
+            stats: bucket.stats.clone(),
+            cpu_us: bucket.cpu_us,
+        }))
+    }
+}
+
+impl<'de> Deserialize<'de> for Rollups {
+    fn deserialize<D>(deserializer: D) -> Result<Rollups, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let entries = Vec::<RollupEntry>::deserialize(deserializer)?;
+        Ok(Rollups(
+            entries
+                .into_iter()
+                .map(|entry| {
+                    let key = RollupKey {
+                        granularity: entry.granularity,
+                        start: entry.start,
+                        scope: entry.scope,
+                    };
+                    let bucket = BucketStats {
+                        stats: entry.stats,
+                        cpu_us: entry.cpu_us,
+                    };
+                    (key, bucket)
+                })
+                .collect(),
+        ))
+    }
+}
+
+/// An improvement or match found for a function.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Discovery {
+    /// Unix timestamp
+    pub time: i64,
+    pub fn_name: String,
+    pub client: UserId,
+    pub server: UserId,
+    pub score: i64,
+    pub hash: Option<String>,
+    /// server CPU time spent on the iteration that found it
+    pub time_us: f64,
+}
+
+/// Keys of the rows that have changed since the last save, for storage
+/// backends that save incrementally.
+#[derive(Clone, Debug, Default)]
+pub struct Dirty {
+    pub users: HashSet<UserId>,
+    pub func_stats: HashSet<String>,
+    pub rollups: HashSet<RollupKey>,
+    /// number of discoveries added to the end of the log
+    pub new_discoveries: usize,
+    /// whether everything is to be written anew, rather than added to
+    pub all: bool,
+}
+
+/// The database. Users and function stats can only be modified through
+/// methods that keep track of what changed.
 #[derive(Debug, Deserialize, Serialize)]
 pub struct DB {
-    pub users: HashMap<UserId, User>,
-    pub func_stats: HashMap<String, Stats>,
+    schema_version: u64,
+    users: HashMap<UserId, User>,
+    func_stats: HashMap<String, Stats>,
     pub total_stats: Stats,
+    rollups: Rollups,
+    discoveries: Vec<Discovery>,
+    #[serde(skip)]
+    dirty: Dirty,
 }
 
 impl DB {
+    pub fn new(users: HashMap<UserId, User>) -> DB {
+        DB {
+            schema_version: SCHEMA_VERSION,
+            users,
+            func_stats: HashMap::new(),
+            total_stats: Default::default(),
+            rollups: Default::default(),
+            discoveries: Vec::new(),
+            dirty: Default::default(),
+        }
+    }
+
+    pub fn schema_version(&self) -> u64 {
+        self.schema_version
+    }
+
+    pub fn users(&self) -> &HashMap<UserId, User> {
+        &self.users
+    }
+
+    pub fn user_mut(&mut self, id: &UserId) -> Option<&mut User> {
+        let user = self.users.get_mut(id)?;
+        self.dirty.users.insert(id.clone());
+        Some(user)
+    }
+
+    /// Add a user, unless one with the same id already exists.
+    pub fn add_user(&mut self, id: UserId, user: User) {
+        if !self.users.contains_key(&id) {
+            self.dirty.users.insert(id.clone());
+            self.users.insert(id, user);
+        }
+    }
+
+    pub fn func_stats(&self) -> &HashMap<String, Stats> {
+        &self.func_stats
+    }
+
     pub fn func_stat(&mut self, fn_name: String) -> &mut Stats {
-        self.func_stats
-            .entry(fn_name)
-            .or_insert_with(Stats::default)
+        self.dirty.func_stats.insert(fn_name.clone());
+        self.func_stats.entry(fn_name).or_default()
+    }
+
+    pub fn rollups(&self) -> &HashMap<RollupKey, BucketStats> {
+        &self.rollups.0
+    }
+
+    /// The stats bucket of the given granularity and scope that contains the
+    /// given timestamp.
+    pub fn rollup(
+        &mut self,
+        granularity: Granularity,
+        timestamp: i64,
+        scope: Scope,
+    ) -> &mut BucketStats {
+        let key = RollupKey {
+            granularity,
+            start: granularity.bucket(timestamp),
+            scope,
+        };
+        self.dirty.rollups.insert(key.clone());
+        self.rollups.0.entry(key).or_default()
+    }
+
+    /// Remove buckets of the given granularity that start before the cutoff.
+    pub fn prune_rollups(&mut self, granularity: Granularity, cutoff: i64) {
+        let dirty = &mut self.dirty;
+        self.rollups.0.retain(|key, _| {
+            let keep = key.granularity != granularity || key.start >= cutoff;
+            if !keep {
+                dirty.rollups.insert(key.clone());
+            }
+            keep
+        });
+    }
+
+    /// The discovery log, oldest first.
+    pub fn discoveries(&self) -> &[Discovery] {
+        &self.discoveries
+    }
+
+    pub fn add_discovery(&mut self, discovery: Discovery) {
+        self.discoveries.push(discovery);
+        self.dirty.new_discoveries += 1;
+    }
+
+    /// Return what has changed since the last call, and start over.
+    pub fn take_dirty(&mut self) -> Dirty {
+        std::mem::take(&mut self.dirty)
+    }
+
+    /// Mark rows as changed again, after saving them failed.
+    pub fn restore_dirty(&mut self, dirty: Dirty) {
+        self.dirty.users.extend(dirty.users);
+        self.dirty.func_stats.extend(dirty.func_stats);
+        self.dirty.rollups.extend(dirty.rollups);
+        self.dirty.new_discoveries += dirty.new_discoveries;
+        self.dirty.all |= dirty.all;
+    }
+
+    /// Mark everything as changed.
+    pub fn all_dirty(&self) -> Dirty {
+        Dirty {
+            users: self.users.keys().cloned().collect(),
+            func_stats: self.func_stats.keys().cloned().collect(),
+            rollups: self.rollups.0.keys().cloned().collect(),
+            new_discoveries: self.discoveries.len(),
+            all: true,
+        }
+    }
+
+    /// Look up a user by hex id or by (unique) name.
+    pub fn find_user(&self, spec: &str) -> Result<UserId, String> {
+        if let Ok(id) = UserId::from_hex(spec) {
+            if self.users.contains_key(&id) {
+                return Ok(id);
+            }
+        }
+        let mut matches = self.users.iter().filter(|(_, user)| user.name == spec);
+        match (matches.next(), matches.next()) {
+            (Some((id, _)), None) => Ok(id.clone()),
+            (Some(_), Some(_)) => Err(format!(
+                "Multiple users are named {}, refer to them by id instead",
+                spec
+            )),
+            (None, _) => Err(format!("No such user: {}", spec)),
+        }
+    }
+
+    /// The users that vouched for the given user, closest first. If the chain
+    /// is broken by a revoked user, that user's id is the last element.
+    pub fn trust_chain(&self, id: &UserId) -> Vec<&UserId> {
+        let mut chain = Vec::new();
+        let mut seen = HashSet::new();
+        let mut cur = self.users.get(id).and_then(|user| user.trusted_by.as_ref());
+        while let Some(voucher) = cur {
+            if !seen.insert(voucher) {
+                break;
+            }
+            chain.push(voucher);
+            cur = self
+                .users
+                .get(voucher)
+                .and_then(|user| user.trusted_by.as_ref());
+        }
+        chain
+    }
+
+    /// Everyone vouched for by the given user, directly or indirectly.
+    pub fn vouchees(&self, id: &UserId) -> Vec<UserId> {
+        let mut ret = Vec::new();
+        let mut seen = HashSet::new();
+        seen.insert(id.clone());
+        let mut stack = vec![id.clone()];
+        while let Some(cur) = stack.pop() {
+            for (vid, user) in &self.users {
+                if user.trusted_by.as_ref() == Some(&cur) && seen.insert(vid.clone()) {
+                    ret.push(vid.clone());
+                    stack.push(vid.clone());
+                }
+            }
+        }
+        ret
+    }
+
+    /// Remove a user, and optionally everyone they vouched for. Returns the
+    /// removed users.
+    pub fn revoke(&mut self, id: &UserId, cascade: bool) -> Vec<(UserId, User)> {
+        let mut to_remove = vec![id.clone()];
+        if cascade {
+            to_remove.extend(self.vouchees(id));
+        }
+        let removed: Vec<(UserId, User)> = to_remove
+            .into_iter()
+            .filter_map(|id| self.users.remove(&id).map(|user| (id, user)))
+            .collect();
+        for (id, _) in &removed {
+            self.dirty.users.insert(id.clone());
+        }
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROOT: &str = "8a88e3dd7409f195fd52db2d3cba5d72ca6709bf1d94121bf3748801b40f6f5c";
+    const ALICE: &str = "8139770ea87d175f56a35466c34c7ecccb8d8a91b4ee37a25df60f5b8fc9b394";
+
+    /// A database as written before schema versioning.
+    fn v0_db() -> Value {
+        serde_json::json!({
+            "users": {
+                ROOT: {
+                    "trusted_by": null,
+                    "name": "root",
+                    "client_stats": [1, 2, 3, 4],
+                    "server_stats": [0, 0, 0, 0],
+                },
+                ALICE: {
+                    "trusted_by": ROOT,
+                    "name": "alice",
+                    "client_stats": [0, 0, 0, 0],
+                    "server_stats": [5, 6, 7, 8],
+                },
+            },
+            "func_stats": { "f": [10, 1, 0, 1] },
+            "total_stats": [10, 1, 0, 1],
+        })
+    }
+
+    #[test]
+    fn migrates_v0_to_current() {
+        let mut value = v0_db();
+        let report = migrate(&mut value).unwrap();
+        assert_eq!(report.from_version, 0);
+        assert_eq!(report.steps.len(), SCHEMA_VERSION as usize);
+        assert!(!report.is_noop());
+
+        let db: DB = serde_json::from_value(value).unwrap();
+        assert_eq!(db.schema_version(), SCHEMA_VERSION);
+        let root = &db.users()[&UserId::from_hex(ROOT).unwrap()];
+        let alice = &db.users()[&UserId::from_hex(ALICE).unwrap()];
+        assert!(root.roles.contains(&Role::Admin));
+        assert!(!alice.roles.contains(&Role::Admin));
+        assert!(has_role(&alice.roles, Role::Submit));
+        assert_eq!(root.client_stats.iterations, 1);
+        assert_eq!(alice.server_stats.functions, 8);
+        assert_eq!(db.func_stats()["f"].iterations, 10);
+        assert!(db.rollups().is_empty());
+        assert!(db.discoveries().is_empty());
+    }
+
+    #[test]
+    fn migrating_again_is_a_noop() {
+        let mut value = v0_db();
+        migrate(&mut value).unwrap();
+        let migrated = value.clone();
+        let report = migrate(&mut value).unwrap();
+        assert!(report.is_noop());
+        assert!(report.steps.is_empty());
+        assert_eq!(value, migrated);
+    }
+
+    #[test]
+    fn refuses_newer_versions() {
+        let mut value = v0_db();
+        value["schema_version"] = (SCHEMA_VERSION + 1).into();
+        assert!(migrate(&mut value).is_err());
     }
 }
@@ -0,0 +1,68 @@
+use crate::db::{MigrationReport, SCHEMA_VERSION};
+use crate::save;
+use crate::storage::{self, JsonStorage, SqliteStorage, Storage};
+use crate::util::SimpleResult;
+use crate::{DbImportOpts, DbMigrateOpts, DbOpts, DbSubCommand};
+
+fn run_import(opts: DbImportOpts) -> SimpleResult<()> {
+    let (db, _) = save::load_file(&opts.from)?;
+    storage::create(&opts.to, &db)?;
+    println!(
+        "Imported {} users and stats for {} functions into {}.",
+        db.users().len(),
+        db.func_stats().len(),
+        opts.to
+    );
+    Ok(())
+}
+
+fn print_report(report: &MigrationReport) {
+    for (description, changes) in &report.steps {
+        println!("- {}", description);
+        for change in changes {
+            println!("    {}", change);
+        }
+    }
+}
+
+fn migrate(storage: &mut impl Storage, dry_run: bool) -> SimpleResult<()> {
+    let (_, report) = if dry_run {
+        save::load(storage)?
+    } else {
+        save::load_and_migrate(storage)?
+    };
+    if report.is_noop() {
+        println!(
+            "Database is up to date (schema version {}).",
+            SCHEMA_VERSION
+        );
+        return Ok(());
+    }
+    println!(
+        "{} database from schema version {} to {}:",
+        if dry_run { "Would migrate" } else { "Migrated" },
+        report.from_version,
+        SCHEMA_VERSION
+    );
+    print_report(&report);
+    Ok(())
+}
+
+fn run_migrate(opts: DbMigrateOpts) -> SimpleResult<()> {
+    if !storage::is_sqlite(&opts.db) {
+        migrate(&mut JsonStorage::open(&opts.db), opts.dry_run)
+    } else if opts.dry_run {
+        // A dry run must leave the database exactly as it was.
+        migrate(&mut SqliteStorage::open_read_only(&opts.db)?, true)
+    } else {
+        migrate(&mut SqliteStorage::open(&opts.db)?, false)
+    }
+}
+
+pub(crate) fn run_db(opts: DbOpts) -> SimpleResult<()> {
+    match opts.sub {
+        DbSubCommand::Import(opts) => run_import(opts)?,
+        DbSubCommand::Migrate(opts) => run_migrate(opts)?,
+    }
+    Ok(())
+}
@@ -0,0 +1,78 @@
+use chrono::{TimeZone, Utc};
+use serde::Serialize;
+
+use crate::db::{UserId, DB};
+use crate::save;
+use crate::util::SimpleResult;
+use crate::DiscoveriesOpts;
+
+#[derive(Serialize)]
+struct DiscoveryView<'a> {
+    time: i64,
+    fn_name: &'a str,
+    client: String,
+    server: String,
+    score: i64,
+    hash: Option<&'a str>,
+    time_us: f64,
+}
+
+fn user_name(db: &DB, id: &UserId) -> String {
+    match db.users().get(id) {
+        Some(user) => user.name.clone(),
+        None => format!("(revoked {})", id.to_hex()),
+    }
+}
+
+pub(crate) fn run_discoveries(opts: DiscoveriesOpts) -> SimpleResult<()> {
+    let (db, _) = save::load_file(&opts.db)?;
+    // Revoked users are shown by id, which must work for filtering too.
+    let user = opts
+        .user
+        .as_ref()
+        .map(|user| UserId::from_hex(user).or_else(|_| db.find_user(user)))
+        .transpose()?;
+
+    let mut discoveries: Vec<DiscoveryView> = db
+        .discoveries()
+        .iter()
+        .rev()
+        .filter(|d| opts.function.as_ref().is_none_or(|f| d.fn_name == *f))
+        .filter(|d| {
+            user.as_ref()
+                .is_none_or(|id| d.client == *id || d.server == *id)
+        })
+        .take(opts.last)
+        .map(|d| DiscoveryView {
+            time: d.time,
+            fn_name: &d.fn_name,
+            client: user_name(&db, &d.client),
+            server: user_name(&db, &d.server),
+            score: d.score,
+            hash: d.hash.as_deref(),
+            time_us: d.time_us,
+        })
+        .collect();
+    discoveries.reverse();
+
+    if opts.json {
+        println!("{}", serde_json::to_string(&discoveries)?);
+        return Ok(());
+    }
+
+    for d in discoveries {
+        println!(
+            "{} {} score {} by {} on {}'s server, hash {}, {:.2}s",
+            Utc.timestamp_opt(d.time, 0)
+                .unwrap()
+                .format("%Y-%m-%d %H:%M:%S"),
+            d.fn_name,
+            d.score,
+            d.client,
+            d.server,
+            d.hash.unwrap_or("-"),
+            d.time_us / 1_000_000.0
+        );
+    }
+    Ok(())
+}
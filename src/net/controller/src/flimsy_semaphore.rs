@@ -53,6 +53,11 @@ impl FlimsySemaphore {
         }
     }
 
+    /// The number of free slots, which is negative when overdrawn.
+    pub fn available(&self) -> isize {
+        self.slots.load(Ordering::Relaxed)
+    }
+
     pub fn release(&self) {
         if self.slots.fetch_add(1, Ordering::Release) == 0 {
             self.notify.notify_one();
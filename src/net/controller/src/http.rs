@@ -0,0 +1,106 @@
+use std::future::Future;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+
+use crate::logging;
+use crate::util::SimpleResult;
+
+const MAX_REQUEST_SIZE: usize = 8192;
+/// How long a client may take to send its request, so that idle connections
+/// don't pile up.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Response {
+    pub status: &'static str,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn ok(content_type: &'static str, body: String) -> Response {
+        Response {
+            status: "200 OK",
+            content_type,
+            body,
+        }
+    }
+
+    pub fn not_found() -> Response {
+        Response {
+            status: "404 Not Found",
+            content_type: "text/plain",
+            body: "not found\n".to_string(),
+        }
+    }
+}
+
+/// Read the request head. We only care about the path, and ignore any
+/// headers or body.
+async fn read_head(socket: &mut TcpStream) -> SimpleResult<Vec<u8>> {
+    let mut buffer = Vec::new();
+    while !buffer.windows(4).any(|w| w == b"\r\n\r\n") {
+        if buffer.len() >= MAX_REQUEST_SIZE {
+            Err("HTTP request too large")?;
+        }
+        let mut chunk = [0; 1024];
+        let len = socket.read(&mut chunk).await?;
+        if len == 0 {
+            Err("HTTP request ended early")?;
+        }
+        buffer.extend_from_slice(&chunk[..len]);
+    }
+    Ok(buffer)
+}
+
+async fn handle_request(
+    mut socket: TcpStream,
+    handler: impl FnOnce(&str) -> Response,
+) -> SimpleResult<()> {
+    let buffer = match timeout(REQUEST_TIMEOUT, read_head(&mut socket)).await {
+        Ok(buffer) => buffer?,
+        Err(_) => Err("HTTP request timed out")?,
+    };
+    let head = String::from_utf8_lossy(&buffer);
+    let mut parts = head.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(path)) => handler(path),
+        _ => Err("Invalid HTTP request")?,
+    };
+
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    );
+    socket.write_all(head.as_bytes()).await?;
+    socket.write_all(response.body.as_bytes()).await?;
+    socket.shutdown().await?;
+    Ok(())
+}
+
+/// Serve plain HTTP GET requests on a local listener, one response per
+/// connection.
+pub async fn serve<H>(listen_on: &str, handler: H) -> SimpleResult<impl Future<Output = ()>>
+where
+    H: Fn(&str) -> Response + Copy + Send + 'static,
+{
+    let listener = TcpListener::bind(listen_on).await?;
+    Ok(async move {
+        loop {
+            let socket = match listener.accept().await {
+                Ok((socket, _)) => socket,
+                Err(e) => {
+                    logging::warn("HTTP accept failed", &[("error", e.to_string().into())]);
+                    continue;
+                }
+            };
+            tokio::spawn(async move {
+                let _ = handle_request(socket, handler).await;
+            });
+        }
+    })
+}
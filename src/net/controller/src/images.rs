@@ -0,0 +1,78 @@
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+
+use crate::util::SimpleResult;
+
+/// The name of the image given by the top-level `docker_image` setting, used
+/// by jobs that don't ask for a particular one.
+pub const DEFAULT_IMAGE: &str = "default";
+
+/// An entry in the `[docker_images]` table of the config.
+#[derive(Clone, Debug, Deserialize)]
+pub struct DockerImage {
+    pub image: String,
+    /// digest to pin the image to, e.g. "sha256:..."
+    pub digest: Option<String>,
+    /// oldest permuter version that may use the image
+    #[serde(default)]
+    pub min_permuter_version: u32,
+}
+
+impl DockerImage {
+    /// The reference servers should pull and run.
+    pub fn reference(&self) -> String {
+        match self.digest {
+            Some(ref digest) => format!("{}@{}", self.image, digest),
+            None => self.image.clone(),
+        }
+    }
+}
+
+/// The docker images a controller knows about, by name.
+pub struct DockerImages {
+    images: BTreeMap<String, DockerImage>,
+}
+
+impl DockerImages {
+    pub fn from_config(
+        default_image: String,
+        mut images: BTreeMap<String, DockerImage>,
+    ) -> SimpleResult<DockerImages> {
+        if images.contains_key(DEFAULT_IMAGE) {
+            Err(format!(
+                "docker_images can't contain \"{}\"; set docker_image instead",
+                DEFAULT_IMAGE
+            ))?;
+        }
+        images.insert(
+            DEFAULT_IMAGE.to_string(),
+            DockerImage {
+                image: default_image,
+                digest: None,
+                min_permuter_version: 0,
+            },
+        );
+        Ok(DockerImages { images })
+    }
+
+    pub fn default_reference(&self) -> String {
+        self.images[DEFAULT_IMAGE].reference()
+    }
+
+    /// Look up an image that a connection running the given permuter version
+    /// wants to use.
+    pub fn get(&self, name: &str, permuter_version: u32) -> Result<&DockerImage, String> {
+        let image = self
+            .images
+            .get(name)
+            .ok_or_else(|| format!("Unknown docker image \"{}\"", name))?;
+        if permuter_version < image.min_permuter_version {
+            return Err(format!(
+                "Docker image \"{}\" requires permuter version {} or later",
+                name, image.min_permuter_version
+            ));
+        }
+        Ok(image)
+    }
+}
@@ -0,0 +1,236 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::future::Future;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+
+use chrono::{Local, Utc};
+use serde_json::{Map, Value};
+
+use crate::util::SimpleResult;
+use crate::PermuterId;
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Level {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Debug => "debug",
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Level, String> {
+        [Level::Debug, Level::Info, Level::Warn, Level::Error]
+            .iter()
+            .copied()
+            .find(|level| level.name() == s)
+            .ok_or_else(|| format!("unknown log level {}", s))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unknown log format {}", s)),
+        }
+    }
+}
+
+struct Logger {
+    level: Level,
+    format: Format,
+    main_sink: Mutex<Box<dyn Write + Send>>,
+    packet_sink: Mutex<Box<dyn Write + Send>>,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+fn logger() -> &'static Logger {
+    LOGGER.get_or_init(|| Logger {
+        level: Level::Info,
+        format: Format::Text,
+        main_sink: Mutex::new(Box::new(io::stderr())),
+        packet_sink: Mutex::new(Box::new(io::stdout())),
+    })
+}
+
+/// Set up logging. Packet traces go to the given file, or stdout if none.
+/// Must be called before anything is logged.
+pub fn init(level: Level, format: Format, packet_log: Option<&str>) -> SimpleResult<()> {
+    let packet_sink: Box<dyn Write + Send> = match packet_log {
+        Some(path) => Box::new(OpenOptions::new().create(true).append(true).open(path)?),
+        None => Box::new(io::stdout()),
+    };
+    LOGGER
+        .set(Logger {
+            level,
+            format,
+            main_sink: Mutex::new(Box::new(io::stderr())),
+            packet_sink: Mutex::new(packet_sink),
+        })
+        .map_err(|_| "logging already initialized")?;
+    Ok(())
+}
+
+/// Per-connection logging context, which is attached to every line logged
+/// from within the connection's task.
+#[derive(Clone, Default)]
+pub struct Context {
+    pub conn: Option<u64>,
+    pub peer: Option<SocketAddr>,
+    pub user: Option<String>,
+    pub request: Option<&'static str>,
+    pub permuter: Option<PermuterId>,
+}
+
+tokio::task_local! {
+    static CONTEXT: RefCell<Context>;
+}
+
+/// Run a future with the given logging context.
+pub async fn scope<F: Future>(context: Context, fut: F) -> F::Output {
+    CONTEXT.scope(RefCell::new(context), fut).await
+}
+
+/// Modify the logging context of the current task, if any.
+pub fn update_context(f: impl FnOnce(&mut Context)) {
+    let _ = CONTEXT.try_with(|c| f(&mut c.borrow_mut()));
+}
+
+fn current_context() -> Context {
+    CONTEXT.try_with(|c| c.borrow().clone()).unwrap_or_default()
+}
+
+fn context_fields(context: &Context) -> Vec<(&'static str, Value)> {
+    let mut fields = Vec::new();
+    if let Some(conn) = context.conn {
+        fields.push(("conn", conn.into()));
+    }
+    if let Some(peer) = context.peer {
+        fields.push(("peer", peer.to_string().into()));
+    }
+    if let Some(request) = context.request {
+        fields.push(("request", request.into()));
+    }
+    if let Some(permuter) = context.permuter {
+        fields.push(("permuter", permuter.into()));
+    }
+    fields
+}
+
+struct TextValue<'a>(&'a Value);
+
+impl fmt::Display for TextValue<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Value::String(s) if !s.is_empty() && !s.contains(char::is_whitespace) => f.write_str(s),
+            value => write!(f, "{}", value),
+        }
+    }
+}
+
+fn format_line(level: Level, msg: &str, fields: &[(&str, Value)]) -> String {
+    let logger = logger();
+    let context = current_context();
+    let all_fields = fields.iter().cloned().chain(context_fields(&context));
+    match logger.format {
+        Format::Json => {
+            let mut obj = Map::new();
+            obj.insert("ts".into(), Utc::now().to_rfc3339().into());
+            obj.insert("level".into(), level.name().into());
+            if let Some(user) = context.user {
+                obj.insert("user".into(), user.into());
+            }
+            obj.insert("msg".into(), msg.into());
+            for (key, value) in all_fields {
+                obj.insert(key.into(), value);
+            }
+            Value::Object(obj).to_string()
+        }
+        Format::Text => {
+            let mut line = format!(
+                "{} {:5} [{}] {}",
+                Local::now().format("%Y-%m-%d %H:%M:%S"),
+                level.name(),
+                context.user.as_deref().unwrap_or("-"),
+                msg
+            );
+            for (key, value) in all_fields {
+                line += &format!(" {}={}", key, TextValue(&value));
+            }
+            line
+        }
+    }
+}
+
+pub fn log(level: Level, msg: &str, fields: &[(&str, Value)]) {
+    let logger = logger();
+    if level < logger.level {
+        return;
+    }
+    let line = format_line(level, msg, fields);
+    let mut sink = logger.main_sink.lock().unwrap();
+    let _ = writeln!(sink, "{}", line);
+}
+
+pub fn info(msg: &str, fields: &[(&str, Value)]) {
+    log(Level::Info, msg, fields);
+}
+
+pub fn warn(msg: &str, fields: &[(&str, Value)]) {
+    log(Level::Warn, msg, fields);
+}
+
+pub fn error(msg: &str, fields: &[(&str, Value)]) {
+    log(Level::Error, msg, fields);
+}
+
+/// Trace a packet sent or received, for --debug.
+pub fn packet(direction: &str, who: &str, msg: &[u8]) {
+    let body: Value = if msg.len() <= 300 {
+        String::from_utf8(
+            msg.iter()
+                .copied()
+                .flat_map(std::ascii::escape_default)
+                .collect(),
+        )
+        .unwrap()
+        .into()
+    } else {
+        format!("{} bytes", msg.len()).into()
+    };
+    let line = format_line(
+        Level::Debug,
+        direction,
+        &[("who", who.into()), ("data", body)],
+    );
+    let mut sink = logger().packet_sink.lock().unwrap();
+    let _ = writeln!(sink, "{}", line);
+    let _ = sink.flush();
+}
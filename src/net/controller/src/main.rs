@@ -1,9 +1,11 @@
 #![allow(clippy::try_err)]
 
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 use std::convert::TryInto;
 use std::default::Default;
+use std::error::Error;
 use std::io::ErrorKind;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 
 use argh::FromArgs;
@@ -11,6 +13,7 @@ use serde::{Deserialize, Serialize};
 use serde_json::json;
 use slotmap::{new_key_type, SlotMap};
 use sodiumoxide::crypto::box_;
+use sodiumoxide::crypto::hash::sha256;
 use sodiumoxide::crypto::sign;
 use tokio::fs;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -19,20 +22,37 @@ use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::{mpsc, watch, Notify};
 use tokio::time;
 
-use crate::db::{ByteString, UserId};
+use crate::db::{has_role, ByteString, Granularity, Limits, Role, Roles, UserId};
 use crate::flimsy_semaphore::FlimsySemaphore;
 use crate::port::{ReadPort, WritePort};
 use crate::save::SaveableDB;
-use crate::util::SimpleResult;
+use crate::util::{MovingAverage, RateCounter, SimpleResult};
 
+mod archive;
 mod client;
+mod credit;
 mod db;
+mod db_cmd;
+mod discoveries_cmd;
 mod flimsy_semaphore;
+mod http;
+mod images;
+mod logging;
+mod metrics;
 mod port;
+mod results_cmd;
+mod revoke;
 mod save;
+mod scheduler;
+mod seeds;
 mod server;
 mod setup;
+mod simulate;
 mod stats;
+mod stats_cmd;
+mod status;
+mod storage;
+mod users;
 mod util;
 mod vouch;
 
@@ -50,6 +70,12 @@ struct CmdOpts {
 enum SubCommand {
     RunServer(RunServerOpts),
     Setup(SetupOpts),
+    Users(UsersOpts),
+    Db(DbOpts),
+    Stats(StatsOpts),
+    Discoveries(DiscoveriesOpts),
+    Results(ResultsOpts),
+    Simulate(SimulateOpts),
 }
 
 #[derive(FromArgs)]
@@ -64,28 +90,359 @@ struct RunServerOpts {
     #[argh(option)]
     config: String,
 
-    /// path to JSON database
+    /// path to database (.sqlite or .sqlite3 for SQLite, JSON otherwise)
     #[argh(option)]
     db: String,
 
-    /// enable debug logging
+    /// enable logging of all packets
     #[argh(switch)]
     debug: bool,
+
+    /// file to append packet logs from --debug to (default: stdout)
+    #[argh(option)]
+    packet_log: Option<String>,
+
+    /// minimum level to log: debug, info, warn or error (default: info)
+    #[argh(option, default = "logging::Level::Info")]
+    log_level: logging::Level,
+
+    /// log format: text or json (default: text)
+    #[argh(option, default = "logging::Format::Text")]
+    log_format: logging::Format,
+
+    /// ip:port to serve Prometheus metrics on over HTTP (e.g. 127.0.0.1:9100)
+    #[argh(option)]
+    metrics_listen: Option<String>,
 }
 
 #[derive(FromArgs)]
 /// Setup initial database and config for permuter@home.
 #[argh(subcommand, name = "setup")]
 struct SetupOpts {
-    /// path to JSON database
+    /// path to database (.sqlite or .sqlite3 for SQLite, JSON otherwise)
+    #[argh(option)]
+    db: String,
+}
+
+#[derive(FromArgs)]
+/// Inspect and manage users in the permuter@home database. The control server
+/// must not be running while users are revoked or renamed.
+#[argh(subcommand, name = "users")]
+struct UsersOpts {
+    /// path to database
     #[argh(option)]
     db: String,
+
+    #[argh(subcommand)]
+    sub: UsersSubCommand,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum UsersSubCommand {
+    List(UsersListOpts),
+    Show(UsersShowOpts),
+    Revoke(UsersRevokeOpts),
+    Rename(UsersRenameOpts),
+    SetRoles(UsersSetRolesOpts),
+    SetLimits(UsersSetLimitsOpts),
+}
+
+#[derive(FromArgs)]
+/// List all users.
+#[argh(subcommand, name = "list")]
+struct UsersListOpts {}
+
+#[derive(FromArgs)]
+/// Show details about a user.
+#[argh(subcommand, name = "show")]
+struct UsersShowOpts {
+    /// user id (hex) or unique name
+    #[argh(positional)]
+    user: String,
+}
+
+#[derive(FromArgs)]
+/// Remove a user from the database.
+#[argh(subcommand, name = "revoke")]
+struct UsersRevokeOpts {
+    /// user id (hex) or unique name
+    #[argh(positional)]
+    user: String,
+
+    /// also revoke everyone the user has vouched for, recursively
+    #[argh(switch)]
+    cascade: bool,
+}
+
+#[derive(FromArgs)]
+/// Change the name of a user.
+#[argh(subcommand, name = "rename")]
+struct UsersRenameOpts {
+    /// user id (hex) or unique name
+    #[argh(positional)]
+    user: String,
+
+    /// new name
+    #[argh(positional)]
+    name: String,
+}
+
+#[derive(FromArgs)]
+/// Change the roles of a user.
+#[argh(subcommand, name = "set-roles")]
+struct UsersSetRolesOpts {
+    /// user id (hex) or unique name
+    #[argh(positional)]
+    user: String,
+
+    /// comma-separated list of roles (vouch, serve, submit, admin)
+    #[argh(positional)]
+    roles: String,
+}
+
+#[derive(FromArgs)]
+/// Change the client limits of a user. Limits that are not given are left
+/// as they are.
+#[argh(subcommand, name = "set-limits")]
+struct UsersSetLimitsOpts {
+    /// user id (hex) or unique name
+    #[argh(positional)]
+    user: String,
+
+    /// first reset all limits to the defaults from the config
+    #[argh(switch)]
+    reset: bool,
+
+    /// maximum number of concurrent permuters
+    #[argh(option)]
+    max_permuters: Option<usize>,
+
+    /// maximum priority
+    #[argh(option)]
+    max_priority: Option<f64>,
+
+    /// maximum server CPU hours per day
+    #[argh(option)]
+    daily_cpu_hours: Option<f64>,
+}
+
+#[derive(FromArgs)]
+/// Maintain the permuter@home database. The control server must not be
+/// running.
+#[argh(subcommand, name = "db")]
+struct DbOpts {
+    #[argh(subcommand)]
+    sub: DbSubCommand,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum DbSubCommand {
+    Import(DbImportOpts),
+    Migrate(DbMigrateOpts),
+}
+
+#[derive(FromArgs)]
+/// Copy a database into a new file, e.g. to move from JSON to SQLite.
+#[argh(subcommand, name = "import")]
+struct DbImportOpts {
+    /// path to existing database
+    #[argh(option)]
+    from: String,
+
+    /// path to new database (.sqlite or .sqlite3 for SQLite, JSON otherwise)
+    #[argh(option)]
+    to: String,
+}
+
+#[derive(FromArgs)]
+/// Bring a database up to date with this version of pahserver. This also
+/// happens automatically when the database is opened. The original is backed
+/// up next to it, with a .v<version>.bak suffix.
+#[argh(subcommand, name = "migrate")]
+struct DbMigrateOpts {
+    /// path to database
+    #[argh(option)]
+    db: String,
+
+    /// only report what would change
+    #[argh(switch)]
+    dry_run: bool,
+}
+
+#[derive(FromArgs)]
+/// Show time-bucketed stats from the permuter@home database, for the whole
+/// network or a single client, server or function. Stats are saved
+/// periodically, so the latest bucket may lag behind a running controller.
+#[argh(subcommand, name = "stats")]
+struct StatsOpts {
+    /// path to database
+    #[argh(option)]
+    db: String,
+
+    /// bucket size: hour or day (default: day)
+    #[argh(option, default = "Granularity::Day")]
+    granularity: Granularity,
+
+    /// number of buckets to show, ending with the current one (default: 14)
+    #[argh(option, default = "14")]
+    last: u32,
+
+    /// show stats for jobs submitted by this user (id or name)
+    #[argh(option)]
+    client: Option<String>,
+
+    /// show stats for work done by this user's servers (id or name)
+    #[argh(option)]
+    server: Option<String>,
+
+    /// show stats for this function
+    #[argh(option)]
+    function: Option<String>,
+
+    /// output JSON instead of a table
+    #[argh(switch)]
+    json: bool,
+}
+
+#[derive(FromArgs)]
+/// Show the log of improvements and matches found, most recent last. Like
+/// other stats, it is saved periodically.
+#[argh(subcommand, name = "discoveries")]
+struct DiscoveriesOpts {
+    /// path to database
+    #[argh(option)]
+    db: String,
+
+    /// only show discoveries for this function
+    #[argh(option)]
+    function: Option<String>,
+
+    /// only show discoveries where this user (id or name) was the client or
+    /// ran the server
+    #[argh(option)]
+    user: Option<String>,
+
+    /// number of discoveries to show (default: 50)
+    #[argh(option, default = "50")]
+    last: usize,
+
+    /// output JSON instead of text
+    #[argh(switch)]
+    json: bool,
+}
+
+#[derive(FromArgs)]
+/// Retrieve sources from the result archive.
+#[argh(subcommand, name = "results")]
+struct ResultsOpts {
+    /// path to the archive directory (archive_dir in the config)
+    #[argh(option)]
+    archive: String,
+
+    #[argh(subcommand)]
+    sub: ResultsSubCommand,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum ResultsSubCommand {
+    List(ResultsListOpts),
+    Get(ResultsGetOpts),
+}
+
+#[derive(FromArgs)]
+/// List archived matches, and the best source for each function and base.
+#[argh(subcommand, name = "list")]
+struct ResultsListOpts {
+    /// only list sources for this function
+    #[argh(option)]
+    function: Option<String>,
+}
+
+#[derive(FromArgs)]
+/// Print an archived source. By default, this is the best source for the
+/// given function.
+#[argh(subcommand, name = "get")]
+struct ResultsGetOpts {
+    /// function to get a source for
+    #[argh(option)]
+    function: Option<String>,
+
+    /// only consider sources for this base hash
+    #[argh(option)]
+    base_hash: Option<String>,
+
+    /// get a match instead of the best source
+    #[argh(switch, long = "match")]
+    matched: bool,
+
+    /// get the source with this id (or unique prefix of it), as listed
+    #[argh(option)]
+    source: Option<String>,
+
+    /// output zlib-compressed data, as stored
+    #[argh(switch)]
+    raw: bool,
+
+    /// file to write to (default: stdout)
+    #[argh(option)]
+    out: Option<String>,
+}
+
+#[derive(FromArgs)]
+/// Simulate a synthetic fleet of servers and clients in virtual time, and
+/// report utilization, fairness and latency for each scheduling policy.
+#[argh(subcommand, name = "simulate")]
+struct SimulateOpts {
+    /// path to TOML scenario file (see simulate_example.toml)
+    #[argh(option)]
+    scenario: String,
+
+    /// scheduling policy: energy or even. Can be given more than once to
+    /// compare policies (default: energy)
+    #[argh(option)]
+    scheduler: Vec<scheduler::SchedulerKind>,
+
+    /// output JSON instead of tables
+    #[argh(switch)]
+    json: bool,
 }
 
 #[derive(Deserialize)]
 struct Config {
     docker_image: String,
+    /// more images that jobs can ask for by name
+    #[serde(default)]
+    docker_images: BTreeMap<String, images::DockerImage>,
     priv_seed: ByteString<32>,
+    #[serde(default = "default_roles")]
+    default_roles: Roles,
+    #[serde(default)]
+    default_limits: Limits,
+    credits: Option<credit::CreditConfig>,
+    #[serde(default)]
+    stats_retention: stats::Retention,
+    /// ip:port to serve a read-only JSON status API on over HTTP
+    status_listen: Option<String>,
+    /// directory to archive matched and best-so-far sources in
+    archive_dir: Option<String>,
+    /// how long to keep the permuter of a disconnected client around for it
+    /// to resume, in seconds
+    client_resume_grace_secs: Option<u64>,
+    /// how long to remember which jobs a disconnected server had loaded, so
+    /// that it can resume without reloading them, in seconds
+    server_resume_grace_secs: Option<u64>,
+    /// policy for dividing server time between jobs
+    #[serde(default)]
+    scheduler: scheduler::SchedulerKind,
+}
+
+fn default_roles() -> Roles {
+    vec![Role::Vouch, Role::Serve, Role::Submit]
+        .into_iter()
+        .collect()
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -95,16 +452,73 @@ struct PermuterData {
     compressed_source: Vec<u8>,
     #[serde(skip)]
     compressed_target_o_bin: Vec<u8>,
+    /// content hashes of the two blobs above, set by hash_blobs
+    #[serde(skip)]
+    source_hash: String,
+    #[serde(skip)]
+    target_o_bin_hash: String,
+    /// tags a server must have to run the job, such as a toolchain
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    required_tags: BTreeSet<String>,
+    /// name of the docker image the job runs in, if not the default one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    docker_image: Option<String>,
     #[serde(flatten)]
     more_props: HashMap<String, serde_json::Value>,
 }
 
+impl PermuterData {
+    fn hash_blobs(&mut self) {
+        self.source_hash = hex::encode(sha256::hash(&self.compressed_source));
+        self.target_o_bin_hash = hex::encode(sha256::hash(&self.compressed_target_o_bin));
+    }
+
+    fn blob(&self, hash: &str) -> Option<&[u8]> {
+        if hash == self.source_hash {
+            Some(&self.compressed_source)
+        } else if hash == self.target_o_bin_hash {
+            Some(&self.compressed_target_o_bin)
+        } else {
+            None
+        }
+    }
+
+    /// A hash identifying the job, so that clients that submit identical
+    /// jobs (same function, source, target object, compile script and
+    /// settings) can share them. Must be called after hash_blobs.
+    fn job_key(&self) -> String {
+        // serde_json maps are sorted, so this is deterministic.
+        let props = serde_json::to_value(self).expect("permuter data is serializable");
+        let key = format!(
+            "{}\n{}\n{}",
+            props, self.source_hash, self.target_o_bin_hash
+        );
+        hex::encode(sha256::hash(key.as_bytes()))
+    }
+
+    fn docker_image(&self) -> &str {
+        self.docker_image
+            .as_deref()
+            .unwrap_or(images::DEFAULT_IMAGE)
+    }
+
+    fn can_run_on(&self, server_tags: &BTreeSet<String>, server_images: &BTreeSet<String>) -> bool {
+        self.required_tags.is_subset(server_tags) && server_images.contains(self.docker_image())
+    }
+
+    fn base_hash(&self) -> Option<&str> {
+        self.more_props
+            .get("base_hash")
+            .and_then(|hash| hash.as_str())
+    }
+}
+
 #[derive(Clone, Copy, Debug, Deserialize, Serialize)]
 struct PermuterWork {
     seed: u64,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 enum ServerUpdate {
     Result {
@@ -126,54 +540,442 @@ enum ServerUpdate {
     Disconnect,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 enum PermuterResult {
     NeedWork,
-    Result(UserId, String, ServerUpdate),
+    /// server name, update, and time spent by the server
+    Result(String, ServerUpdate, f64),
 }
 
 type PermuterId = u64;
 
-struct Permuter {
-    data: Arc<PermuterData>,
+/// A client connection attached to a permuter.
+struct AttachedClient {
+    /// identifies the attachment within the permuter
+    id: u64,
     client_id: UserId,
     client_name: String,
+    priority: f64,
     work_queue: VecDeque<PermuterWork>,
     result_tx: mpsc::UnboundedSender<PermuterResult>,
     semaphore: Arc<FlimsySemaphore>,
+    /// if set, work is generated from this once the work queue is empty
+    seeds: Option<seeds::SeedGenerator>,
+}
+
+impl AttachedClient {
+    fn next_work(&mut self) -> Option<PermuterWork> {
+        if let Some(work) = self.work_queue.pop_front() {
+            self.semaphore.release();
+            return Some(work);
+        }
+        // Generated work takes no space in the queue, but don't let results
+        // pile up if the client is slow to receive them.
+        if self.semaphore.available() <= 0 {
+            return None;
+        }
+        let seed = self.seeds.as_mut()?.next()?;
+        Some(PermuterWork { seed })
+    }
+}
+
+/// A job, shared between all clients that submitted it. Work is taken from
+/// the clients in turn, and results are sent to all of them.
+struct Permuter {
+    data: Arc<PermuterData>,
+    job_key: String,
+    /// the user whose client asked for the highest priority, whose share of
+    /// server time the job is scheduled under
+    client_id: UserId,
+    client_name: String,
+    clients: Vec<AttachedClient>,
+    next_attachment_id: u64,
+    /// index into clients of the one to take work from next
+    next_client: usize,
     priority: f64,
     energy_add: f64,
+    /// number of servers that have the permuter loaded
+    loaded_servers: usize,
+    results: RateCounter,
+    /// moving average of the time an iteration takes, across servers
+    time_us: MovingAverage,
+    /// the same for each server, since machines differ in speed
+    server_time_us: HashMap<ServerId, MovingAverage>,
 }
 
 impl Permuter {
+    fn new(
+        data: Arc<PermuterData>,
+        client_id: UserId,
+        client_name: String,
+        priority: f64,
+    ) -> Permuter {
+        Permuter {
+            job_key: data.job_key(),
+            data,
+            client_id,
+            client_name,
+            clients: Vec::new(),
+            next_attachment_id: 0,
+            next_client: 0,
+            priority,
+            energy_add: 1.0 / priority,
+            loaded_servers: 0,
+            results: RateCounter::new(),
+            time_us: MovingAverage::default(),
+            server_time_us: HashMap::new(),
+        }
+    }
+
+    fn record_time(&mut self, server: ServerId, time_us: f64) {
+        self.time_us.add(time_us);
+        self.server_time_us.entry(server).or_default().add(time_us);
+    }
+
+    /// Estimated time an iteration takes on a server, falling back to the
+    /// estimate across servers. None until some server has reported a result.
+    fn time_estimate(&self, server: ServerId) -> Option<f64> {
+        self.server_time_us
+            .get(&server)
+            .and_then(MovingAverage::value)
+            .or_else(|| self.time_us.value())
+    }
+
+    /// Attach a client, and return its attachment id.
+    fn attach(
+        &mut self,
+        client_id: UserId,
+        client_name: String,
+        priority: f64,
+        result_tx: mpsc::UnboundedSender<PermuterResult>,
+        seeds: Option<seeds::SeedGenerator>,
+    ) -> u64 {
+        let id = self.next_attachment_id;
+        self.next_attachment_id += 1;
+        self.clients.push(AttachedClient {
+            id,
+            client_id,
+            client_name,
+            priority,
+            work_queue: VecDeque::new(),
+            result_tx,
+            semaphore: Arc::new(FlimsySemaphore::new(client::CLIENT_MAX_QUEUES_SIZE)),
+            seeds,
+        });
+        self.update_owner();
+        id
+    }
+
+    /// Detach a client. Returns whether any clients are left.
+    fn detach(&mut self, attachment_id: u64) -> bool {
+        self.clients.retain(|client| client.id != attachment_id);
+        self.update_owner();
+        !self.clients.is_empty()
+    }
+
+    /// The job runs at the highest priority among the clients attached to
+    /// it, and belongs to the client that asked for that, the earliest one in
+    /// case of a tie. This way, no user's job runs above the priority they
+    /// asked for once they have detached.
+    fn update_owner(&mut self) {
+        let mut owner: Option<&AttachedClient> = None;
+        for client in &self.clients {
+            if owner.is_none_or(|owner| client.priority > owner.priority) {
+                owner = Some(client);
+            }
+        }
+        if let Some(owner) = owner {
+            self.client_id = owner.client_id.clone();
+            self.client_name = owner.client_name.clone();
+            self.priority = owner.priority;
+            self.energy_add = 1.0 / owner.priority;
+        }
+    }
+
+    /// Split time spent on the job evenly between the users attached to it.
+    pub(crate) fn split_time(&self, time_us: f64) -> BTreeMap<UserId, f64> {
+        let share = time_us / self.clients.len().max(1) as f64;
+        let mut split = BTreeMap::new();
+        for client in &self.clients {
+            *split.entry(client.client_id.clone()).or_default() += share;
+        }
+        split
+    }
+
+    fn client_mut(&mut self, attachment_id: u64) -> Option<&mut AttachedClient> {
+        self.clients
+            .iter_mut()
+            .find(|client| client.id == attachment_id)
+    }
+
+    fn work_queue_len(&self) -> usize {
+        self.clients
+            .iter()
+            .map(|client| client.work_queue.len())
+            .sum()
+    }
+
+    /// Take the next work item, going round the clients.
+    fn pop_work(&mut self) -> Option<PermuterWork> {
+        let num_clients = self.clients.len();
+        for i in 0..num_clients {
+            let index = (self.next_client + i) % num_clients;
+            if let Some(work) = self.clients[index].next_work() {
+                self.next_client = index + 1;
+                return Some(work);
+            }
+        }
+        None
+    }
+
     fn send_result(&mut self, res: PermuterResult) {
-        // We can't use a blocking semaphore acquire here, because we don't
-        // want server sends to block on random client receives. In practice,
-        // this is probably fine.
-        let _ = self.result_tx.send(res);
-        self.semaphore.acquire_ignore_limit();
+        for client in &self.clients {
+            // We can't use a blocking semaphore acquire here, because we don't
+            // want server sends to block on random client receives. In practice,
+            // this is probably fine.
+            let _ = client.result_tx.send(res.clone());
+            client.semaphore.acquire_ignore_limit();
+        }
     }
 }
 
 new_key_type! { struct ServerId; }
 
 struct ConnectedServer {
+    owner_name: String,
     min_priority: f64,
     num_cores: f64,
+    tags: BTreeSet<String>,
+    docker_images: BTreeSet<String>,
+    /// permuters the server can run but hasn't been sent yet
+    pending_adds: BTreeSet<PermuterId>,
+    /// permuters that have been removed but that the server may have loaded
+    pending_removes: BTreeSet<PermuterId>,
+}
+
+impl ConnectedServer {
+    fn can_run(&self, perm: &Permuter) -> bool {
+        perm.data.can_run_on(&self.tags, &self.docker_images)
+    }
 }
 
 struct MutableState {
     servers: SlotMap<ServerId, ConnectedServer>,
     permuters: HashMap<PermuterId, Permuter>,
     next_permuter_id: PermuterId,
+    /// Permuters by job key, to find identical jobs to share.
+    job_keys: HashMap<String, PermuterId>,
+    /// Permuters by the user that started them.
+    user_permuters: HashMap<UserId, BTreeSet<PermuterId>>,
+    /// Permuters each user has clients attached to, with the number of
+    /// attached clients.
+    user_attachments: HashMap<UserId, HashMap<PermuterId, usize>>,
+    /// Kick signals for all live connections, by user.
+    sessions: HashMap<UserId, Vec<Arc<Notify>>>,
+    /// Priority multipliers from contribution credit, if enabled.
+    credit_boosts: HashMap<UserId, f64>,
+    /// Disconnected clients that may still resume, by resume token.
+    suspended_clients: HashMap<String, client::SuspendedClient>,
+    /// Disconnected servers that may still resume, by resume token.
+    suspended_servers: HashMap<String, server::SuspendedServer>,
+}
+
+impl MutableState {
+    fn new() -> MutableState {
+        MutableState {
+            servers: SlotMap::with_key(),
+            permuters: HashMap::new(),
+            next_permuter_id: 0,
+            job_keys: HashMap::new(),
+            user_permuters: HashMap::new(),
+            user_attachments: HashMap::new(),
+            sessions: HashMap::new(),
+            credit_boosts: HashMap::new(),
+            suspended_clients: HashMap::new(),
+            suspended_servers: HashMap::new(),
+        }
+    }
+
+    /// The scheduling priority of a user, which is the highest priority
+    /// among their permuters, boosted by contribution credit.
+    fn user_priority(&self, user_id: &UserId) -> f64 {
+        let boost = self.credit_boosts.get(user_id).copied().unwrap_or(1.0);
+        self.user_permuters
+            .get(user_id)
+            .into_iter()
+            .flatten()
+            .map(|perm_id| self.permuters[perm_id].priority)
+            .fold(0.0, f64::max)
+            * boost
+    }
+
+    /// Register a connected server. It is sent all permuters it can run.
+    fn add_server(&mut self, mut server: ConnectedServer) -> ServerId {
+        server.pending_adds = self
+            .permuters
+            .iter()
+            .filter(|(_, perm)| server.can_run(perm))
+            .map(|(&perm_id, _)| perm_id)
+            .collect();
+        self.servers.insert(server)
+    }
+
+    /// Register a new permuter, and queue it up for the servers that can run
+    /// it.
+    fn add_permuter(&mut self, perm: Permuter) -> PermuterId {
+        let perm_id = self.next_permuter_id;
+        self.next_permuter_id += 1;
+        for server in self.servers.values_mut() {
+            if server.can_run(&perm) {
+                server.pending_adds.insert(perm_id);
+            }
+        }
+        self.job_keys.insert(perm.job_key.clone(), perm_id);
+        self.user_permuters
+            .entry(perm.client_id.clone())
+            .or_default()
+            .insert(perm_id);
+        self.permuters.insert(perm_id, perm);
+        perm_id
+    }
+
+    /// Attach a client to a permuter, and return its attachment id.
+    fn attach_client(
+        &mut self,
+        perm_id: PermuterId,
+        client_id: UserId,
+        client_name: String,
+        priority: f64,
+        result_tx: mpsc::UnboundedSender<PermuterResult>,
+        seeds: Option<seeds::SeedGenerator>,
+    ) -> u64 {
+        *self
+            .user_attachments
+            .entry(client_id.clone())
+            .or_default()
+            .entry(perm_id)
+            .or_default() += 1;
+        let perm = self.permuters.get_mut(&perm_id).unwrap();
+        let old_owner = perm.client_id.clone();
+        let attachment_id = perm.attach(client_id, client_name, priority, result_tx, seeds);
+        self.owner_changed(perm_id, old_owner);
+        attachment_id
+    }
+
+    /// Detach a client from its permuter, removing the permuter if no
+    /// clients are left.
+    fn detach_client(&mut self, perm_id: PermuterId, attachment_id: u64) {
+        let perm = match self.permuters.get_mut(&perm_id) {
+            Some(perm) => perm,
+            None => return,
+        };
+        let client_id = match perm.client_mut(attachment_id) {
+            Some(client) => client.client_id.clone(),
+            None => return,
+        };
+        let old_owner = perm.client_id.clone();
+        let remaining = perm.detach(attachment_id);
+        self.forget_attachment(&client_id, perm_id);
+        if remaining {
+            self.owner_changed(perm_id, old_owner);
+        } else {
+            self.remove_permuter(perm_id);
+        }
+    }
+
+    /// Detach all clients of a user, removing the permuters that have no
+    /// clients left and handing the rest over to their remaining clients.
+    fn detach_user(&mut self, user_id: &UserId) {
+        let attachments: Vec<(PermuterId, u64)> = self
+            .user_attachments
+            .get(user_id)
+            .into_iter()
+            .flat_map(HashMap::keys)
+            .flat_map(|perm_id| {
+                self.permuters[perm_id]
+                    .clients
+                    .iter()
+                    .filter(|client| client.client_id == *user_id)
+                    .map(move |client| (*perm_id, client.id))
+            })
+            .collect();
+        for (perm_id, attachment_id) in attachments {
+            self.detach_client(perm_id, attachment_id);
+        }
+    }
+
+    /// The number of permuters a user has clients attached to.
+    fn attached_permuters(&self, user_id: &UserId) -> usize {
+        self.user_attachments.get(user_id).map_or(0, HashMap::len)
+    }
+
+    fn forget_attachment(&mut self, user_id: &UserId, perm_id: PermuterId) {
+        let attachments = self.user_attachments.get_mut(user_id).unwrap();
+        let count = attachments.get_mut(&perm_id).unwrap();
+        *count -= 1;
+        if *count == 0 {
+            attachments.remove(&perm_id);
+            if attachments.is_empty() {
+                self.user_attachments.remove(user_id);
+            }
+        }
+    }
+
+    fn owner_changed(&mut self, perm_id: PermuterId, old_owner: UserId) {
+        let owner = &self.permuters[&perm_id].client_id;
+        if *owner == old_owner {
+            return;
+        }
+        self.user_permuters
+            .entry(owner.clone())
+            .or_default()
+            .insert(perm_id);
+        let old_permuters = self.user_permuters.get_mut(&old_owner).unwrap();
+        old_permuters.remove(&perm_id);
+        if old_permuters.is_empty() {
+            self.user_permuters.remove(&old_owner);
+        }
+    }
+
+    /// Remove a permuter, and queue up its removal from the servers that may
+    /// have it loaded.
+    fn remove_permuter(&mut self, perm_id: PermuterId) -> Option<Permuter> {
+        let perm = self.permuters.remove(&perm_id)?;
+        for server in self.servers.values_mut() {
+            if server.can_run(&perm) && !server.pending_adds.remove(&perm_id) {
+                server.pending_removes.insert(perm_id);
+            }
+        }
+        self.job_keys.remove(&perm.job_key);
+        for client in &perm.clients {
+            self.forget_attachment(&client.client_id, perm_id);
+        }
+        let user_permuters = self.user_permuters.get_mut(&perm.client_id).unwrap();
+        user_permuters.remove(&perm_id);
+        if user_permuters.is_empty() {
+            self.user_permuters.remove(&perm.client_id);
+        }
+        Some(perm)
+    }
 }
 
 struct State {
-    docker_image: String,
+    docker_images: images::DockerImages,
+    scheduler: Box<dyn scheduler::Scheduler>,
+    /// Roles given to newly vouched-for users.
+    default_roles: Roles,
+    /// Limits for users that don't have their own.
+    default_limits: Limits,
     debug: bool,
     sign_sk: sign::SecretKey,
     db: SaveableDB,
     stats_tx: mpsc::Sender<stats::Record>,
+    metrics: metrics::Metrics,
+    archive: Option<archive::Archive>,
+    /// How long disconnected clients may take to resume, if they can.
+    client_resume_grace: Option<time::Duration>,
+    /// How long disconnected servers may take to resume, if they can.
+    server_resume_grace: Option<time::Duration>,
     heartbeat_rx: watch::Receiver<()>,
     new_work_notification: Notify,
     m: Mutex<MutableState>,
@@ -186,6 +988,61 @@ impl State {
             .await
             .map_err(|_| "stats thread died".into())
     }
+
+    fn register_session(&self, user_id: &UserId) -> Session<'_> {
+        let kick = Arc::new(Notify::new());
+        let mut m = self.m.lock().unwrap();
+        m.sessions
+            .entry(user_id.clone())
+            .or_default()
+            .push(kick.clone());
+        Session {
+            state: self,
+            user_id: user_id.clone(),
+            kick,
+        }
+    }
+
+    /// Tear down all live connections of a user.
+    fn kick_user(&self, user_id: &UserId) {
+        let mut m = self.m.lock().unwrap();
+        if let Some(sessions) = m.sessions.get(user_id) {
+            for kick in sessions {
+                kick.notify_one();
+            }
+        }
+        client::drop_suspended(&mut m, user_id);
+        m.detach_user(user_id);
+        server::drop_suspended(&mut m, user_id);
+        self.new_work_notification.notify_waiters();
+    }
+}
+
+/// A live connection, which is deregistered on drop.
+struct Session<'a> {
+    state: &'a State,
+    user_id: UserId,
+    kick: Arc<Notify>,
+}
+
+impl Session<'_> {
+    /// Resolves with an error once the session's user has been kicked.
+    async fn kicked(&self) -> SimpleResult<()> {
+        self.kick.notified().await;
+        Err("access revoked".into())
+    }
+}
+
+impl Drop for Session<'_> {
+    fn drop(&mut self) {
+        let mut m = self.state.m.lock().unwrap();
+        if let Some(sessions) = m.sessions.get_mut(&self.user_id) {
+            sessions.retain(|kick| !Arc::ptr_eq(kick, &self.kick));
+            if sessions.is_empty() {
+                m.sessions.remove(&self.user_id);
+            }
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -193,6 +1050,8 @@ impl State {
 enum Request {
     Ping,
     Vouch(vouch::VouchData),
+    Revoke(revoke::RevokeData),
+    SetRoles(revoke::SetRolesData),
     ConnectServer(server::ConnectServerData),
     ConnectClient(client::ConnectClientData),
 }
@@ -213,42 +1072,88 @@ async fn main() -> SimpleResult<()> {
     match opts.sub {
         SubCommand::RunServer(opts) => run_server(opts).await?,
         SubCommand::Setup(opts) => setup::run_setup(opts)?,
+        SubCommand::Users(opts) => users::run_users(opts).await?,
+        SubCommand::Db(opts) => db_cmd::run_db(opts)?,
+        SubCommand::Stats(opts) => stats_cmd::run_stats(opts)?,
+        SubCommand::Discoveries(opts) => discoveries_cmd::run_discoveries(opts)?,
+        SubCommand::Results(opts) => results_cmd::run_results(opts)?,
+        SubCommand::Simulate(opts) => simulate::run_simulate(opts).await?,
     }
     Ok(())
 }
 
 async fn run_server(opts: RunServerOpts) -> SimpleResult<()> {
+    logging::init(opts.log_level, opts.log_format, opts.packet_log.as_deref())?;
     let config: Config = toml::from_str(&fs::read_to_string(&opts.config).await?)?;
     let (_, sign_sk) = sign::keypair_from_seed(&config.priv_seed.to_seed());
 
     let (save_fut, db) = SaveableDB::open(&opts.db)?;
     tokio::spawn(async move {
         if let Err(e) = save_fut.await {
-            eprintln!("Failed to save! {:?}", e);
+            logging::error("failed to save", &[("error", format!("{:?}", e).into())]);
             std::process::exit(1);
         }
     });
 
-    let (stats_fut, stats_tx) = stats::stats_thread(&db);
+    let credit_half_life = config.credits.as_ref().map(|c| c.half_life_hours);
+    let (stats_fut, stats_tx) = stats::stats_thread(&db, credit_half_life, config.stats_retention);
     tokio::spawn(stats_fut);
 
+    let archive = config
+        .archive_dir
+        .as_deref()
+        .map(archive::Archive::open)
+        .transpose()?;
+
+    let docker_images =
+        images::DockerImages::from_config(config.docker_image, config.docker_images)?;
+
     let (heartbeat_tx, heartbeat_rx) = watch::channel(());
 
     let state: &'static State = Box::leak(Box::new(State {
-        docker_image: config.docker_image,
+        docker_images,
+        scheduler: config.scheduler.build(),
+        default_roles: config.default_roles,
+        default_limits: config.default_limits,
         debug: opts.debug,
         sign_sk,
         db,
         stats_tx,
+        metrics: Default::default(),
+        archive,
+        client_resume_grace: config
+            .client_resume_grace_secs
+            .map(time::Duration::from_secs),
+        server_resume_grace: config
+            .server_resume_grace_secs
+            .map(time::Duration::from_secs),
         heartbeat_rx,
         new_work_notification: Notify::new(),
-        m: Mutex::new(MutableState {
-            servers: SlotMap::with_key(),
-            permuters: HashMap::new(),
-            next_permuter_id: 0,
-        }),
+        m: Mutex::new(MutableState::new()),
     }));
 
+    if let Some(credit_config) = config.credits {
+        tokio::spawn(async move {
+            credit::refresh_boosts_loop(state, &credit_config).await;
+        });
+    }
+
+    if let Some(metrics_listen) = opts.metrics_listen {
+        let fut = http::serve(&metrics_listen, move |path| {
+            metrics::handle_request(state, path)
+        })
+        .await?;
+        tokio::spawn(fut);
+    }
+
+    if let Some(status_listen) = config.status_listen {
+        let fut = http::serve(&status_listen, move |path| {
+            status::handle_request(state, path)
+        })
+        .await?;
+        tokio::spawn(fut);
+    }
+
     tokio::spawn(async move {
         loop {
             heartbeat_tx.send(()).expect("receiver is still alive");
@@ -258,29 +1163,46 @@ async fn run_server(opts: RunServerOpts) -> SimpleResult<()> {
 
     let listener = TcpListener::bind(opts.listen_on).await?;
 
+    let next_conn_id = AtomicU64::new(0);
     loop {
-        let (socket, _) = listener.accept().await?;
-        tokio::spawn(async move {
-            let mut who = "anonymous".to_string();
-            if let Err(e) = handle_connection(socket, state, &mut who).await {
-                if let Some(e) = e.downcast_ref::<std::io::Error>() {
-                    if matches!(
-                        e.kind(),
-                        ErrorKind::UnexpectedEof
-                            | ErrorKind::ConnectionReset
-                            | ErrorKind::TimedOut
-                            | ErrorKind::BrokenPipe
-                    ) {
-                        eprintln!("[{}] disconnected", &who);
-                        return;
-                    }
+        let (socket, peer) = listener.accept().await?;
+        let context = logging::Context {
+            conn: Some(next_conn_id.fetch_add(1, Ordering::Relaxed)),
+            peer: Some(peer),
+            ..Default::default()
+        };
+        tokio::spawn(logging::scope(context, async move {
+            if let Err(e) = handle_connection(socket, state).await {
+                let kind = error_kind(&*e);
+                if matches!(kind, "eof" | "reset" | "timeout") {
+                    logging::info("disconnected", &[("kind", kind.into())]);
+                } else {
+                    logging::error(
+                        "error",
+                        &[("kind", kind.into()), ("error", e.to_string().into())],
+                    );
                 }
-                eprintln!("[{}] error: {:?}", &who, e);
             }
-        });
+        }));
     }
 }
 
+fn error_kind(e: &(dyn Error + 'static)) -> &'static str {
+    match e.downcast_ref::<std::io::Error>().map(|e| e.kind()) {
+        Some(ErrorKind::UnexpectedEof) => "eof",
+        Some(ErrorKind::ConnectionReset) | Some(ErrorKind::BrokenPipe) => "reset",
+        Some(ErrorKind::TimedOut) => "timeout",
+        Some(_) => "io",
+        None => "protocol",
+    }
+}
+
+/// Whether an error means the connection dropped, rather than that the peer
+/// misbehaved or was kicked.
+fn is_disconnect(e: &(dyn Error + 'static)) -> bool {
+    matches!(error_kind(e), "eof" | "reset" | "timeout" | "io")
+}
+
 fn concat<T: Clone>(a: &[T], b: &[T]) -> Vec<T> {
     a.iter().chain(b).cloned().collect()
 }
@@ -300,11 +1222,11 @@ async fn handshake<'a>(
     if magic != b"p@h0" {
         Err("Invalid protocol version")?;
     }
-    let their_pk = box_::PublicKey::from_slice(&their_pk).unwrap();
+    let their_pk = box_::PublicKey::from_slice(their_pk).unwrap();
 
     let (our_pk, our_sk) = box_::gen_keypair();
     let signed_data = concat3(b"HELLO:", their_pk.as_ref(), our_pk.as_ref());
-    let signature = sign::sign_detached(&signed_data, &sign_sk);
+    let signature = sign::sign_detached(&signed_data, sign_sk);
     wr.write_all(&concat(our_pk.as_ref(), signature.as_ref()))
         .await?;
 
@@ -339,7 +1261,7 @@ fn current_load(state: &State, priority: Option<f64>) -> Load {
     let mut servers: usize = 0;
     let mut cores: f64 = 0.0;
     for server in m.servers.values() {
-        if priority.map_or(true, |p| p >= server.min_priority) {
+        if priority.is_none_or(|p| p >= server.min_priority) {
             servers += 1;
             cores += server.num_cores;
         }
@@ -351,26 +1273,32 @@ fn current_load(state: &State, priority: Option<f64>) -> Load {
     }
 }
 
-async fn handle_connection(
-    mut socket: TcpStream,
-    state: &State,
-    out_name: &mut String,
-) -> SimpleResult<()> {
+async fn handle_connection(mut socket: TcpStream, state: &'static State) -> SimpleResult<()> {
     let (rd, wr) = socket.split();
     let (mut read_port, mut write_port, user_id, permuter_version) =
-        handshake(rd, wr, &state.sign_sk).await?;
-    let name = match state.db.read(|db| {
-        let user = db.users.get(&user_id)?;
-        Some(user.name.clone())
+        match handshake(rd, wr, &state.sign_sk).await {
+            Ok(res) => res,
+            Err(e) => {
+                state.metrics.record_handshake_failure();
+                return Err(e);
+            }
+        };
+    // Register the session before checking the user against the database, so
+    // that a concurrent revocation either makes the check fail or kicks us.
+    let session = state.register_session(&user_id);
+    let (name, roles) = match state.db.read(|db| {
+        let user = db.users().get(&user_id)?;
+        Some((user.name.clone(), user.roles.clone()))
     }) {
-        Some(name) => name,
+        Some(tup) => tup,
         None => {
+            state.metrics.record_unknown_user();
             write_port.send_error("Access denied!").await?;
             Err("Unknown client!")?
         }
     };
-    *out_name = name.clone();
-    eprintln!("[{}] connected (v {})", &name, permuter_version);
+    logging::update_context(|c| c.user = Some(name.clone()));
+    logging::info("connected", &[("version", permuter_version.into())]);
     if state.debug {
         read_port.set_debug(&name);
         write_port.set_debug(&name);
@@ -379,14 +1307,46 @@ async fn handle_connection(
 
     let request = read_port.recv().await?;
     let request: Request = serde_json::from_slice(&request)?;
+    let request_type = match request {
+        Request::Ping => "ping",
+        Request::Vouch(_) => "vouch",
+        Request::Revoke(_) => "revoke",
+        Request::SetRoles(_) => "set_roles",
+        Request::ConnectServer(_) => "connect_server",
+        Request::ConnectClient(_) => "connect_client",
+    };
+    logging::update_context(|c| c.request = Some(request_type));
+    let required_role = match request {
+        Request::Ping | Request::Revoke(_) => None,
+        Request::Vouch(_) => Some(Role::Vouch),
+        Request::SetRoles(_) => Some(Role::Admin),
+        Request::ConnectServer(_) => Some(Role::Serve),
+        Request::ConnectClient(_) => Some(Role::Submit),
+    };
+    if let Some(role) = required_role {
+        if !has_role(&roles, role) {
+            write_port.send_error("Permission denied!").await?;
+            Err(format!("Missing role {}", role))?;
+        }
+    }
     match request {
         Request::Ping => {
-            eprintln!("[{}] ping", &name);
+            logging::info("ping", &[]);
             let load = current_load(state, None);
             write_port.send_json(&load).await?;
         }
         Request::Vouch(data) => {
-            vouch::handle_vouch(read_port, write_port, user_id, &name, state, data).await?;
+            tokio::select! {
+                r = vouch::handle_vouch(read_port, write_port, user_id, state, data) => r?,
+                r = session.kicked() => r?,
+            }
+        }
+        Request::Revoke(data) => {
+            let is_admin = roles.contains(&Role::Admin);
+            revoke::handle_revoke(write_port, user_id, is_admin, state, data).await?;
+        }
+        Request::SetRoles(data) => {
+            revoke::handle_set_roles(write_port, state, data).await?;
         }
         Request::ConnectServer(data) => {
             server::handle_connect_server(
@@ -396,6 +1356,7 @@ async fn handle_connection(
                 &name,
                 permuter_version,
                 state,
+                &session,
                 data,
             )
             .await?;
@@ -408,6 +1369,7 @@ async fn handle_connection(
                 &name,
                 permuter_version,
                 state,
+                &session,
                 data,
             )
             .await?;
@@ -0,0 +1,219 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::http::Response;
+use crate::stats::Outcome;
+use crate::State;
+
+/// Counters for events that are not otherwise kept track of.
+#[derive(Default)]
+pub struct Metrics {
+    matched: AtomicU64,
+    improved: AtomicU64,
+    unhelpful: AtomicU64,
+    handshake_failures: AtomicU64,
+    unknown_users: AtomicU64,
+    blob_bytes_needed: AtomicU64,
+    blob_bytes_sent: AtomicU64,
+}
+
+impl Metrics {
+    pub fn record_outcome(&self, outcome: Outcome) {
+        let counter = match outcome {
+            Outcome::Matched => &self.matched,
+            Outcome::Improved => &self.improved,
+            Outcome::Unhelpful => &self.unhelpful,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_handshake_failure(&self) {
+        self.handshake_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_unknown_user(&self) {
+        self.unknown_users.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record that a server needs blobs of the given size to load a job.
+    pub fn record_blobs_needed(&self, bytes: usize) {
+        self.blob_bytes_needed
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Record blobs actually sent to a server, rather than found in its cache.
+    pub fn record_blobs_sent(&self, bytes: usize) {
+        self.blob_bytes_sent
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+fn single(out: &mut String, name: &str, kind: &str, help: &str, value: f64) {
+    header(out, name, kind, help);
+    writeln!(out, "{} {}", name, value).unwrap();
+}
+
+/// Render all metrics in the Prometheus text format.
+pub fn render(state: &State) -> String {
+    let mut out = String::new();
+    let metrics = &state.metrics;
+
+    {
+        let m = state.m.lock().unwrap();
+        let cores = m
+            .servers
+            .values()
+            .fold(0.0, |acc, server| acc + server.num_cores);
+        single(
+            &mut out,
+            "pah_servers",
+            "gauge",
+            "Connected servers.",
+            m.servers.len() as f64,
+        );
+        single(
+            &mut out,
+            "pah_cores",
+            "gauge",
+            "Cores across connected servers.",
+            cores,
+        );
+        single(
+            &mut out,
+            "pah_permuters",
+            "gauge",
+            "Active permuters.",
+            m.permuters.len() as f64,
+        );
+
+        let mut perms: Vec<_> = m.permuters.iter().collect();
+        perms.sort_by_key(|(&perm_id, _)| perm_id);
+        let labels: Vec<String> = perms
+            .iter()
+            .map(|(perm_id, perm)| {
+                format!(
+                    "permuter=\"{}\",fn_name=\"{}\",client=\"{}\"",
+                    perm_id,
+                    escape_label(&perm.data.fn_name),
+                    escape_label(&perm.client_name)
+                )
+            })
+            .collect();
+        header(
+            &mut out,
+            "pah_permuter_work_queue",
+            "gauge",
+            "Work items queued for a permuter.",
+        );
+        for ((_, perm), labels) in perms.iter().zip(&labels) {
+            let len = perm.work_queue_len();
+            writeln!(out, "pah_permuter_work_queue{{{}}} {}", labels, len).unwrap();
+        }
+        header(
+            &mut out,
+            "pah_permuter_semaphore_slots",
+            "gauge",
+            "Free slots in the combined work/result queues of a permuter's clients.",
+        );
+        for ((_, perm), labels) in perms.iter().zip(&labels) {
+            let slots: isize = perm
+                .clients
+                .iter()
+                .map(|client| client.semaphore.available())
+                .sum();
+            writeln!(out, "pah_permuter_semaphore_slots{{{}}} {}", labels, slots).unwrap();
+        }
+    }
+
+    header(
+        &mut out,
+        "pah_results_total",
+        "counter",
+        "Results sent to clients, by outcome.",
+    );
+    for (outcome, counter) in &[
+        ("matched", &metrics.matched),
+        ("improved", &metrics.improved),
+        ("unhelpful", &metrics.unhelpful),
+    ] {
+        let value = counter.load(Ordering::Relaxed);
+        writeln!(
+            out,
+            "pah_results_total{{outcome=\"{}\"}} {}",
+            outcome, value
+        )
+        .unwrap();
+    }
+
+    header(
+        &mut out,
+        "pah_handshake_failures_total",
+        "counter",
+        "Connections that failed the handshake, by reason.",
+    );
+    for (reason, counter) in &[
+        ("protocol", &metrics.handshake_failures),
+        ("unknown_user", &metrics.unknown_users),
+    ] {
+        let value = counter.load(Ordering::Relaxed);
+        writeln!(
+            out,
+            "pah_handshake_failures_total{{reason=\"{}\"}} {}",
+            reason, value
+        )
+        .unwrap();
+    }
+
+    header(
+        &mut out,
+        "pah_blob_bytes_total",
+        "counter",
+        "Bytes of sources and target objects needed by servers to load jobs, and \
+         how many of them were sent rather than cached.",
+    );
+    for (kind, counter) in &[
+        ("needed", &metrics.blob_bytes_needed),
+        ("sent", &metrics.blob_bytes_sent),
+    ] {
+        let value = counter.load(Ordering::Relaxed);
+        writeln!(out, "pah_blob_bytes_total{{kind=\"{}\"}} {}", kind, value).unwrap();
+    }
+
+    let save_stats = state.db.save_stats();
+    header(
+        &mut out,
+        "pah_db_save_seconds",
+        "summary",
+        "Time spent saving the database.",
+    );
+    writeln!(out, "pah_db_save_seconds_sum {}", save_stats.total_secs).unwrap();
+    writeln!(out, "pah_db_save_seconds_count {}", save_stats.count).unwrap();
+    single(
+        &mut out,
+        "pah_db_last_save_seconds",
+        "gauge",
+        "Time spent on the most recent database save.",
+        save_stats.last_secs,
+    );
+
+    out
+}
+
+pub fn handle_request(state: &State, path: &str) -> Response {
+    match path {
+        "/metrics" => Response::ok("text/plain; version=0.0.4", render(state)),
+        _ => Response::not_found(),
+    }
+}
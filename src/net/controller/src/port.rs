@@ -1,30 +1,14 @@
 use std::convert::TryInto;
 
-use chrono::Local;
 use serde::Serialize;
 use sodiumoxide::crypto::box_;
 use sodiumoxide::crypto::box_::{Nonce, PrecomputedKey};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::tcp::{ReadHalf, WriteHalf};
 
+use crate::logging;
 use crate::util::SimpleResult;
 
-fn debug_print(action: &str, who: &str, msg: &[u8]) {
-    let time = Local::now().format("%H:%M:%S:%f");
-    if msg.len() <= 300 {
-        let msg = String::from_utf8(
-            msg.iter()
-                .copied()
-                .flat_map(std::ascii::escape_default)
-                .collect(),
-        )
-        .unwrap();
-        println!("{} debug: {} {}: {}", time, action, who, msg);
-    } else {
-        println!("{} debug: {} {}: {} bytes", time, action, who, msg.len());
-    }
-}
-
 pub struct ReadPort<'a> {
     read_half: ReadHalf<'a>,
     key: PrecomputedKey,
@@ -58,7 +42,7 @@ impl<'a> ReadPort<'a> {
         let data =
             box_::open_precomputed(&buffer, &nonce, &self.key).map_err(|()| "Failed to decrypt")?;
         if let Some(name) = self.debug_name {
-            debug_print("Receive from", name, &data);
+            logging::packet("receive from", name, &data);
         }
         Ok(data)
     }
@@ -87,7 +71,7 @@ impl<'a> WritePort<'a> {
 
     pub async fn send(&mut self, data: &[u8]) -> SimpleResult<()> {
         if let Some(name) = self.debug_name {
-            debug_print("Send to", name, &data);
+            logging::packet("send to", name, data);
         }
         let nonce = nonce_from_u64(self.nonce);
         self.nonce += 2;
@@ -97,9 +81,9 @@ impl<'a> WritePort<'a> {
         Ok(())
     }
 
-    pub async fn send_json<T: ?Sized>(&mut self, value: &T) -> SimpleResult<()>
+    pub async fn send_json<T>(&mut self, value: &T) -> SimpleResult<()>
     where
-        T: Serialize,
+        T: Serialize + ?Sized,
     {
         self.send(&serde_json::to_vec(value)?).await
     }
@@ -0,0 +1,101 @@
+use std::io::Write;
+use std::path::Path;
+
+use chrono::{TimeZone, Utc};
+
+use crate::archive::{self, Entry, Kind};
+use crate::util::SimpleResult;
+use crate::{ResultsGetOpts, ResultsListOpts, ResultsOpts, ResultsSubCommand};
+
+fn short(hash: &str) -> &str {
+    &hash[..hash.len().min(12)]
+}
+
+fn kind_name(kind: Kind) -> &'static str {
+    match kind {
+        Kind::Match => "match",
+        Kind::Best => "best",
+    }
+}
+
+fn run_list(dir: &Path, opts: ResultsListOpts) -> SimpleResult<()> {
+    for entry in archive::live_entries(dir)? {
+        let info = &entry.info;
+        if opts.function.as_ref().is_some_and(|f| info.fn_name != *f) {
+            continue;
+        }
+        println!(
+            "{} {:5} {} score {} base {} client {} server {} source {}",
+            Utc.timestamp_opt(info.time, 0)
+                .unwrap()
+                .format("%Y-%m-%d %H:%M:%S"),
+            kind_name(entry.kind),
+            info.fn_name,
+            info.score,
+            info.base_hash.as_deref().map_or("-", short),
+            short(&info.client.to_hex()),
+            short(&info.server.to_hex()),
+            short(&entry.blob)
+        );
+    }
+    Ok(())
+}
+
+fn find_entry(dir: &Path, opts: &ResultsGetOpts) -> SimpleResult<Entry> {
+    let entries = archive::live_entries(dir)?;
+    if let Some(blob) = &opts.source {
+        let mut matches = entries
+            .into_iter()
+            .filter(|e| e.blob.starts_with(blob.as_str()));
+        return match (matches.next(), matches.next()) {
+            (Some(entry), None) => Ok(entry),
+            (Some(a), Some(b)) if a.blob == b.blob => Ok(a),
+            (Some(_), Some(_)) => Err(format!("Source id {} is ambiguous", blob).into()),
+            (None, _) => Err(format!("No archived source with id {}", blob).into()),
+        };
+    }
+    let fn_name = opts
+        .function
+        .as_ref()
+        .ok_or("Either --function or --source must be given")?;
+    let kind = if opts.matched {
+        Kind::Match
+    } else {
+        Kind::Best
+    };
+    entries
+        .into_iter()
+        .filter(|e| e.kind == kind && e.info.fn_name == *fn_name)
+        .filter(|e| {
+            opts.base_hash
+                .as_ref()
+                .is_none_or(|h| e.info.base_hash.as_ref() == Some(h))
+        })
+        // Prefer the lowest score, and then the most recent.
+        .min_by_key(|e| (e.info.score, -e.info.time))
+        .ok_or_else(|| format!("No archived {} for {}", kind_name(kind), fn_name).into())
+}
+
+fn run_get(dir: &Path, opts: ResultsGetOpts) -> SimpleResult<()> {
+    let entry = find_entry(dir, &opts)?;
+    let compressed_source = std::fs::read(archive::blob_path(dir, &entry.blob))?;
+    let data = if opts.raw {
+        compressed_source
+    } else {
+        archive::decompress(&compressed_source)?
+    };
+    match &opts.out {
+        Some(path) => std::fs::write(path, data)?,
+        None => std::io::stdout().write_all(&data)?,
+    }
+    Ok(())
+}
+
+pub(crate) fn run_results(opts: ResultsOpts) -> SimpleResult<()> {
+    let dir = Path::new(&opts.archive);
+    match opts.sub {
+        ResultsSubCommand::List(sub) => run_list(dir, sub)?,
+        ResultsSubCommand::Get(sub) => run_get(dir, sub)?,
+    }
+    Ok(())
+}
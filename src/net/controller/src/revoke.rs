@@ -0,0 +1,101 @@
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::db::{has_role, Role, Roles, UserId};
+use crate::logging;
+use crate::port::WritePort;
+use crate::util::SimpleResult;
+use crate::State;
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct RevokeData {
+    /// hex id or name
+    who: String,
+    #[serde(default)]
+    cascade: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct SetRolesData {
+    /// hex id or name
+    who: String,
+    roles: Roles,
+}
+
+pub(crate) async fn handle_revoke(
+    mut write_port: WritePort<'_>,
+    who_id: UserId,
+    is_admin: bool,
+    state: &State,
+    data: RevokeData,
+) -> SimpleResult<()> {
+    let res = state
+        .db
+        .write(true, |db| {
+            let id = db.find_user(&data.who)?;
+            if !is_admin && !db.trust_chain(&id).contains(&&who_id) {
+                return Err("can only revoke users you have vouched for".to_string());
+            }
+            Ok(db.revoke(&id, data.cascade))
+        })
+        .await;
+    let removed = match res {
+        Ok(removed) => removed,
+        Err(e) => {
+            write_port.send_error(&e).await?;
+            Err(e)?
+        }
+    };
+
+    // The users are gone from the database, so they cannot reconnect. Kick
+    // any sessions that are still live.
+    for (id, _) in &removed {
+        state.kick_user(id);
+    }
+
+    let names: Vec<String> = removed.into_iter().map(|(_, user)| user.name).collect();
+    logging::info("revoke", &[("revoked", names.clone().into())]);
+    write_port.send_json(&json!({ "revoked": names })).await?;
+    Ok(())
+}
+
+pub(crate) async fn handle_set_roles(
+    mut write_port: WritePort<'_>,
+    state: &State,
+    data: SetRolesData,
+) -> SimpleResult<()> {
+    let res = state
+        .db
+        .write(true, |db| {
+            let id = db.find_user(&data.who)?;
+            let user = db.user_mut(&id).unwrap();
+            let old_roles = std::mem::replace(&mut user.roles, data.roles.clone());
+            Ok::<_, String>((id, user.name.clone(), old_roles))
+        })
+        .await;
+    let (id, name, old_roles) = match res {
+        Ok(tup) => tup,
+        Err(e) => {
+            write_port.send_error(&e).await?;
+            Err(e)?
+        }
+    };
+
+    // Roles are checked when connecting, so live sessions may be relying on
+    // a role that was just taken away. Kick them; they can reconnect with
+    // whatever roles remain.
+    let lost_role = Role::ALL
+        .iter()
+        .any(|&role| has_role(&old_roles, role) && !has_role(&data.roles, role));
+    if lost_role {
+        state.kick_user(&id);
+    }
+
+    let roles: Vec<String> = data.roles.iter().map(Role::to_string).collect();
+    logging::info(
+        "set roles",
+        &[("target", name.into()), ("roles", roles.clone().into())],
+    );
+    write_port.send_json(&json!({ "roles": roles })).await?;
+    Ok(())
+}
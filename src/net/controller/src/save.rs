@@ -1,63 +1,79 @@
 use std::future::Future;
-use std::io::Write;
-use std::path::{Path, PathBuf};
+use std::pin::Pin;
 use std::sync::{Arc, RwLock};
-use std::time::Duration;
+use std::time::Instant;
 
-use tempfile::NamedTempFile;
 use tokio::sync::{mpsc, oneshot};
-use tokio::time::timeout;
+use tokio::time::{self, timeout};
 
-use crate::db::DB;
+use crate::db::{self, MigrationReport, DB};
+use crate::logging;
+use crate::storage::{self, JsonStorage, SqliteStorage, Storage};
 use crate::util::{FutureExt, SimpleResult};
 
-const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+pub type SaveFuture = Pin<Box<dyn Future<Output = SimpleResult<()>> + Send>>;
 
 enum SaveType {
     Delayed,
     Immediate(oneshot::Sender<()>),
 }
 
+/// Timing information about database saves.
+#[derive(Clone, Copy, Default)]
+pub struct SaveStats {
+    pub count: u64,
+    pub total_secs: f64,
+    pub last_secs: f64,
+}
+
 struct InnerSaveableDB {
     db: DB,
     stale: bool,
     save_chan: mpsc::UnboundedSender<SaveType>,
+    save_stats: SaveStats,
 }
 
 #[derive(Clone)]
 pub struct SaveableDB(Arc<RwLock<InnerSaveableDB>>);
 
-async fn save_db_loop(
+async fn save_db_loop<S: Storage>(
     db: SaveableDB,
-    path: &Path,
+    mut storage: S,
     mut save_channel: mpsc::UnboundedReceiver<SaveType>,
 ) -> SimpleResult<()> {
+    let mut done_chans = Vec::new();
+    let mut retry = false;
     loop {
-        let mut done_chans = Vec::new();
-        match save_channel.recv().await {
-            None => return Ok(()),
-            Some(SaveType::Immediate(chan)) => {
-                done_chans.push(chan);
-            }
-            Some(SaveType::Delayed) => {
-                // Wait for SAVE_INTERVAL or until we receive an Immediate save.
-                let _ = timeout(SAVE_INTERVAL, async {
-                    loop {
-                        match save_channel.recv().await {
-                            None => {
-                                break;
-                            }
-                            Some(SaveType::Immediate(chan)) => {
-                                done_chans.push(chan);
-                                break;
-                            }
-                            Some(SaveType::Delayed) => {}
-                        };
-                    }
-                })
-                .await;
-            }
-        };
+        if retry {
+            // The last save failed. Try again after a while, with whatever
+            // has changed since.
+            time::sleep(S::SAVE_INTERVAL).await;
+        } else {
+            match save_channel.recv().await {
+                None => return Ok(()),
+                Some(SaveType::Immediate(chan)) => {
+                    done_chans.push(chan);
+                }
+                Some(SaveType::Delayed) => {
+                    // Wait for SAVE_INTERVAL or until we receive an Immediate save.
+                    let _ = timeout(S::SAVE_INTERVAL, async {
+                        loop {
+                            match save_channel.recv().await {
+                                None => {
+                                    break;
+                                }
+                                Some(SaveType::Immediate(chan)) => {
+                                    done_chans.push(chan);
+                                    break;
+                                }
+                                Some(SaveType::Delayed) => {}
+                            };
+                        }
+                    })
+                    .await;
+                }
+            };
+        }
 
         // Clear the queue in case more messages have stacked up past an
         // Immediate. Receiver::try_recv() is temporarily dead as of tokio 1.4
@@ -75,35 +91,92 @@ async fn save_db_loop(
             };
         }
 
-        // Mark the DB as non-stale, to start receiving save messages again.
-        db.0.write().unwrap().stale = false;
+        // Mark the DB as non-stale, to start receiving save messages again,
+        // and capture what to save.
+        let start = Instant::now();
+        let (snapshot, dirty) = {
+            let mut inner = db.0.write().unwrap();
+            inner.stale = false;
+            let dirty = inner.db.take_dirty();
+            (storage.snapshot(&inner.db, dirty.clone()), dirty)
+        };
 
-        // Actually do the save, by first serializing, then atomically saving
-        // the file by creating and renaming a temp file in the same directory.
-        let data = db.read(|db| serde_json::to_string(&db).unwrap());
+        // If the write fails, keep the changes around for the next save.
+        retry = false;
+        if let Err(e) = tokio::task::block_in_place(|| storage.write(snapshot)) {
+            logging::warn(
+                "failed to save, retrying",
+                &[("error", e.to_string().into())],
+            );
+            db.0.write().unwrap().db.restore_dirty(dirty);
+            retry = true;
+            continue;
+        }
 
-        let r: SimpleResult<()> = tokio::task::block_in_place(|| {
-            let parent_dir = path.parent().unwrap_or_else(|| Path::new("."));
-            let mut tempf = NamedTempFile::new_in(parent_dir)?;
-            tempf.write_all(data.as_bytes())?;
-            tempf.as_file().sync_all()?;
-            tempf.persist(path)?;
-            Ok(())
-        });
-        r?;
+        {
+            let secs = start.elapsed().as_secs_f64();
+            let stats = &mut db.0.write().unwrap().save_stats;
+            stats.count += 1;
+            stats.total_secs += secs;
+            stats.last_secs = secs;
+        }
 
-        for chan in done_chans {
+        for chan in done_chans.drain(..) {
             let _ = chan.send(());
         }
     }
 }
 
+/// Load a database from storage, bringing it up to date in memory if it was
+/// written by an older version of pahserver.
+pub fn load(storage: &mut impl Storage) -> SimpleResult<(DB, MigrationReport)> {
+    let mut db = storage.load()?;
+    let report = db::migrate(&mut db)?;
+    Ok((serde_json::from_value(db)?, report))
+}
+
+/// Load a database file without modifying it, for read-only use.
+pub fn load_file(path: &str) -> SimpleResult<(DB, MigrationReport)> {
+    if storage::is_sqlite(path) {
+        load(&mut SqliteStorage::open_read_only(path)?)
+    } else {
+        load(&mut JsonStorage::open(path))
+    }
+}
+
+/// Load a database from storage, and if it needs migrating, back it up and
+/// save the migrated version in its place.
+pub fn load_and_migrate(storage: &mut impl Storage) -> SimpleResult<(DB, MigrationReport)> {
+    let (db, report) = load(storage)?;
+    if !report.is_noop() {
+        let backup = storage.backup(&format!(".v{}.bak", report.from_version))?;
+        let snapshot = storage.snapshot(&db, db.all_dirty());
+        storage.write(snapshot)?;
+        logging::info(
+            "migrated database",
+            &[
+                ("from_version", report.from_version.into()),
+                ("to_version", db::SCHEMA_VERSION.into()),
+                ("backup", backup.display().to_string().into()),
+            ],
+        );
+    }
+    Ok((db, report))
+}
+
 impl SaveableDB {
-    pub fn open(
-        filename: &str,
-    ) -> SimpleResult<(impl Future<Output = SimpleResult<()>>, SaveableDB)> {
-        let db_file = std::fs::File::open(filename)?;
-        let db: DB = serde_json::from_reader(&db_file)?;
+    /// Open a database, using the storage backend implied by its file
+    /// extension: SQLite for .sqlite or .sqlite3, and JSON otherwise.
+    pub fn open(filename: &str) -> SimpleResult<(SaveFuture, SaveableDB)> {
+        if storage::is_sqlite(filename) {
+            SaveableDB::open_with(SqliteStorage::open(filename)?)
+        } else {
+            SaveableDB::open_with(JsonStorage::open(filename))
+        }
+    }
+
+    fn open_with<S: Storage>(mut storage: S) -> SimpleResult<(SaveFuture, SaveableDB)> {
+        let (db, _) = load_and_migrate(&mut storage)?;
 
         let (save_tx, save_rx) = mpsc::unbounded_channel();
 
@@ -111,15 +184,19 @@ impl SaveableDB {
             db,
             stale: false,
             save_chan: save_tx,
+            save_stats: SaveStats::default(),
         })));
 
-        let path = PathBuf::from(filename);
         let db2 = saveable_db.clone();
 
-        let fut = async move { save_db_loop(db2, &path, save_rx).await };
+        let fut = Box::pin(async move { save_db_loop(db2, storage, save_rx).await });
         Ok((fut, saveable_db))
     }
 
+    pub fn save_stats(&self) -> SaveStats {
+        self.0.read().unwrap().save_stats
+    }
+
     pub fn read<T>(&self, callback: impl FnOnce(&DB) -> T) -> T {
         let inner = self.0.read().unwrap();
         callback(&inner.db)
@@ -0,0 +1,482 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::db::UserId;
+use crate::{MutableState, PermuterId, ServerId};
+
+/// How long an iteration is assumed to take before any have been reported.
+pub(crate) const TIME_US_GUESS: f64 = 100_000.0;
+const MIN_OVERHEAD_US: f64 = 100_000.0;
+const MAX_OVERHEAD_FACTOR: i64 = 2;
+/// How far energies may drift from zero before they are shifted back, to
+/// avoid problems with float imprecision.
+const MAX_ENERGY: f64 = 1e12;
+
+/// A policy for dividing a server's time between the jobs it has loaded.
+pub(crate) trait Scheduler: Send + Sync {
+    /// Create the queue of jobs for a newly connected server.
+    fn new_queue(&self) -> Box<dyn JobQueue>;
+
+    /// How many more work items to ask for after a result, given how long
+    /// the work item took and how much longer it spent in queues.
+    fn more_work(&self, time_us: f64, overhead_us: i64) -> f64 {
+        // If the work item spent less than some given amount of time in
+        // queues, request more work. This ensures we saturate all server
+        // cores. On the other hand, if it spends too much time in queues,
+        // it's best if we reduce the amount of work.
+        // We don't need to adjust for time spent on the network, because we
+        // have backpressure on slow writes on both ends, and read
+        // continuously.
+        let min_overhead_us = (time_us + MIN_OVERHEAD_US) as i64;
+        if overhead_us == 0 {
+            // Legacy server, skip this logic.
+            1.0
+        } else if overhead_us > MAX_OVERHEAD_FACTOR * min_overhead_us {
+            0.5
+        } else if overhead_us < min_overhead_us {
+            1.5
+        } else {
+            1.0
+        }
+    }
+}
+
+/// What the scheduler needs to know about a job, read from the global state
+/// so that a server's queue can then be updated without holding it.
+pub(crate) struct JobInfo {
+    /// the user whose share of server time the job runs under
+    pub(crate) owner: UserId,
+    pub(crate) user_priority: f64,
+    pub(crate) energy_add: f64,
+    /// how long an iteration is expected to take on the server
+    pub(crate) time_estimate: Option<f64>,
+}
+
+impl JobInfo {
+    pub(crate) fn new(m: &MutableState, server: ServerId, perm_id: PermuterId) -> JobInfo {
+        let perm = &m.permuters[&perm_id];
+        JobInfo {
+            owner: perm.client_id.clone(),
+            user_priority: m.user_priority(&perm.client_id),
+            energy_add: perm.energy_add,
+            time_estimate: perm.time_estimate(server),
+        }
+    }
+}
+
+/// The jobs of a server, indexed by which one to work on next, so that
+/// picking one doesn't need to look at all of them. Job states are kept by
+/// the caller, who tells the queue about changes. Queues only see the global
+/// state through snapshots, so they can be used without holding its lock.
+pub(crate) trait JobQueue: Send {
+    /// A job has been sent to the server to load.
+    fn add(&mut self, perm_id: PermuterId, user_id: &UserId);
+
+    /// A job has been removed from the server.
+    fn remove(&mut self, perm_id: PermuterId);
+
+    /// Mark a job as loaded and ready for work, or as no longer able to take
+    /// any. In the latter case, its work in flight is forgotten.
+    fn set_ready(&mut self, perm_id: PermuterId, ready: bool);
+
+    /// The ready jobs, in the order to try them in when looking for work.
+    fn ready_jobs(&self) -> Box<dyn Iterator<Item = PermuterId> + '_>;
+
+    /// A work item of a job has been handed to the server.
+    fn work_started(&mut self, perm_id: PermuterId, info: &JobInfo);
+
+    /// The result of a work item of a job has come back.
+    fn work_finished(&mut self, perm_id: PermuterId);
+
+    /// Account for time the server spent on a job.
+    fn charge(&mut self, perm_id: PermuterId, info: &JobInfo, time_us: f64);
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum SchedulerKind {
+    /// share server time fairly, by user priority and then by job priority
+    #[default]
+    Energy,
+    /// spread work evenly over jobs, ignoring priorities and users
+    Even,
+}
+
+impl SchedulerKind {
+    pub(crate) fn build(self) -> Box<dyn Scheduler> {
+        match self {
+            SchedulerKind::Energy => Box::new(EnergyScheduler),
+            SchedulerKind::Even => Box::new(EvenScheduler),
+        }
+    }
+}
+
+impl FromStr for SchedulerKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SchedulerKind, String> {
+        match s {
+            "energy" => Ok(SchedulerKind::Energy),
+            "even" => Ok(SchedulerKind::Even),
+            _ => Err(format!("unknown scheduler {}", s)),
+        }
+    }
+}
+
+/// An energy, ordered so that it can be used as a key.
+#[derive(Clone, Copy, PartialEq)]
+struct Energy(f64);
+
+impl Eq for Energy {}
+
+impl PartialOrd for Energy {
+    fn partial_cmp(&self, other: &Energy) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Energy {
+    fn cmp(&self, other: &Energy) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// Keeps track of how much server time each user and job has used, as
+/// energy, and hands out work to whoever is most behind.
+pub(crate) struct EnergyScheduler;
+
+impl Scheduler for EnergyScheduler {
+    fn new_queue(&self) -> Box<dyn JobQueue> {
+        Box::new(EnergyQueue::default())
+    }
+}
+
+struct JobEnergy {
+    user_id: UserId,
+    energy: f64,
+    ready: bool,
+    /// number of work items in flight
+    active_work: i64,
+    /// energy charged ahead of time for the work in flight, to the job and
+    /// to its user
+    active_energy: (f64, f64),
+}
+
+struct UserEnergy {
+    energy: f64,
+    /// number of jobs the user has on the server
+    jobs: usize,
+    /// the user's ready jobs, by energy
+    ready: BTreeSet<(Energy, PermuterId)>,
+    /// energy of the user's job that was last picked, which new jobs start
+    /// out with
+    floor: f64,
+}
+
+/// First picks the user that is most behind, weighted by user priority, and
+/// then that user's job that is most behind, weighted by permuter priority.
+/// Work in flight is charged ahead of time, by how long the job's iterations
+/// have been taking on the server, and refunded once its result comes back
+/// and the actual time is charged.
+#[derive(Default)]
+struct EnergyQueue {
+    jobs: HashMap<PermuterId, JobEnergy>,
+    users: HashMap<UserId, UserEnergy>,
+    /// users with ready jobs, by energy
+    ready_users: BTreeSet<(Energy, UserId)>,
+    /// energy of the user that was last picked, which new users start out
+    /// with
+    floor: f64,
+}
+
+impl EnergyQueue {
+    /// Add energy to a job and to its user, keeping the indices up to date.
+    fn add_energy(&mut self, perm_id: PermuterId, job_energy: f64, user_energy: f64) {
+        let job = self.jobs.get_mut(&perm_id).unwrap();
+        let user = self.users.get_mut(&job.user_id).unwrap();
+        if job.ready {
+            user.ready.remove(&(Energy(job.energy), perm_id));
+            user.ready
+                .insert((Energy(job.energy + job_energy), perm_id));
+        }
+        job.energy += job_energy;
+        if !user.ready.is_empty() {
+            self.ready_users
+                .remove(&(Energy(user.energy), job.user_id.clone()));
+            self.ready_users
+                .insert((Energy(user.energy + user_energy), job.user_id.clone()));
+        }
+        user.energy += user_energy;
+    }
+
+    /// Forget about a job's work in flight, refunding what was charged for it.
+    fn clear_active(&mut self, perm_id: PermuterId) {
+        let job = self.jobs.get_mut(&perm_id).unwrap();
+        let (job_energy, user_energy) = job.active_energy;
+        job.active_work = 0;
+        job.active_energy = (0.0, 0.0);
+        self.add_energy(perm_id, -job_energy, -user_energy);
+    }
+
+    /// Count a new job of a user, returning the energy it starts out with.
+    fn join_user(&mut self, user_id: &UserId) -> f64 {
+        let floor = self.floor;
+        let user = self
+            .users
+            .entry(user_id.clone())
+            .or_insert_with(|| UserEnergy {
+                energy: floor,
+                jobs: 0,
+                ready: BTreeSet::new(),
+                floor: 0.0,
+            });
+        user.jobs += 1;
+        user.floor
+    }
+
+    fn leave_user(&mut self, user_id: &UserId) {
+        let user = self.users.get_mut(user_id).unwrap();
+        user.jobs -= 1;
+        if user.jobs == 0 {
+            self.users.remove(user_id);
+        }
+    }
+
+    /// Add a job to its user's ready jobs or take it out of them, keeping
+    /// the ready users up to date.
+    fn index_ready(&mut self, perm_id: PermuterId, ready: bool) {
+        let job = &self.jobs[&perm_id];
+        let user = self.users.get_mut(&job.user_id).unwrap();
+        let was_ready = !user.ready.is_empty();
+        if ready {
+            user.ready.insert((Energy(job.energy), perm_id));
+        } else {
+            user.ready.remove(&(Energy(job.energy), perm_id));
+        }
+        let is_ready = !user.ready.is_empty();
+        if was_ready != is_ready {
+            let key = (Energy(user.energy), job.user_id.clone());
+            if is_ready {
+                self.ready_users.insert(key);
+            } else {
+                self.ready_users.remove(&key);
+            }
+        }
+    }
+
+    /// Move a job over to the user that owns it now, if that has changed
+    /// since it was added, e.g. because its starter detached from it. Its
+    /// charge for work in flight moves along with it.
+    fn sync_owner(&mut self, perm_id: PermuterId, owner: &UserId) {
+        let job = &self.jobs[&perm_id];
+        if job.user_id == *owner {
+            return;
+        }
+        let (ready, active_energy) = (job.ready, job.active_energy.1);
+        self.add_energy(perm_id, 0.0, -active_energy);
+        if ready {
+            self.index_ready(perm_id, false);
+        }
+        let old_owner = std::mem::replace(
+            &mut self.jobs.get_mut(&perm_id).unwrap().user_id,
+            owner.clone(),
+        );
+        self.leave_user(&old_owner);
+        let energy = self.join_user(owner);
+        self.jobs.get_mut(&perm_id).unwrap().energy = energy;
+        if ready {
+            self.index_ready(perm_id, true);
+        }
+        self.add_energy(perm_id, 0.0, active_energy);
+    }
+
+    /// Shift energies back towards zero once they have drifted far from it.
+    /// Only differences in energy matter, so this doesn't change the order.
+    fn rebase(&mut self, user_id: &UserId) {
+        let user = self.users.get_mut(user_id).unwrap();
+        if user.floor.abs() > MAX_ENERGY {
+            let floor = user.floor;
+            for job in self.jobs.values_mut() {
+                if job.user_id == *user_id {
+                    job.energy -= floor;
+                }
+            }
+            user.ready = user
+                .ready
+                .iter()
+                .map(|&(Energy(energy), perm_id)| (Energy(energy - floor), perm_id))
+                .collect();
+            user.floor = 0.0;
+        }
+        if self.floor.abs() > MAX_ENERGY {
+            let floor = self.floor;
+            for user in self.users.values_mut() {
+                user.energy -= floor;
+            }
+            self.ready_users = self
+                .ready_users
+                .iter()
+                .map(|(Energy(energy), user_id)| (Energy(energy - floor), user_id.clone()))
+                .collect();
+            self.floor = 0.0;
+        }
+    }
+}
+
+impl JobQueue for EnergyQueue {
+    fn add(&mut self, perm_id: PermuterId, user_id: &UserId) {
+        let energy = self.join_user(user_id);
+        self.jobs.insert(
+            perm_id,
+            JobEnergy {
+                user_id: user_id.clone(),
+                energy,
+                ready: false,
+                active_work: 0,
+                active_energy: (0.0, 0.0),
+            },
+        );
+    }
+
+    fn remove(&mut self, perm_id: PermuterId) {
+        self.set_ready(perm_id, false);
+        let job = self.jobs.remove(&perm_id).unwrap();
+        self.leave_user(&job.user_id);
+    }
+
+    fn set_ready(&mut self, perm_id: PermuterId, ready: bool) {
+        if !ready {
+            self.clear_active(perm_id);
+        }
+        let job = self.jobs.get_mut(&perm_id).unwrap();
+        if job.ready == ready {
+            return;
+        }
+        job.ready = ready;
+        self.index_ready(perm_id, ready);
+    }
+
+    fn ready_jobs(&self) -> Box<dyn Iterator<Item = PermuterId> + '_> {
+        Box::new(self.ready_users.iter().flat_map(move |(_, user_id)| {
+            self.users[user_id]
+                .ready
+                .iter()
+                .map(|&(_, perm_id)| perm_id)
+        }))
+    }
+
+    fn work_started(&mut self, perm_id: PermuterId, info: &JobInfo) {
+        self.sync_owner(perm_id, &info.owner);
+        let job = self.jobs.get_mut(&perm_id).unwrap();
+        let user = self.users.get_mut(&job.user_id).unwrap();
+        user.floor = job.energy;
+        self.floor = user.energy;
+
+        let time_us = info.time_estimate.unwrap_or(TIME_US_GUESS);
+        let job_energy = time_us * info.energy_add;
+        let user_energy = time_us / info.user_priority;
+        job.active_work += 1;
+        job.active_energy.0 += job_energy;
+        job.active_energy.1 += user_energy;
+        let user_id = job.user_id.clone();
+        self.add_energy(perm_id, job_energy, user_energy);
+        self.rebase(&user_id);
+    }
+
+    fn work_finished(&mut self, perm_id: PermuterId) {
+        let job = self.jobs.get_mut(&perm_id).unwrap();
+        if job.active_work == 0 {
+            return;
+        }
+        // Work items of a job are charged about the same, so refund the
+        // average.
+        let n = job.active_work as f64;
+        let (job_energy, user_energy) = (job.active_energy.0 / n, job.active_energy.1 / n);
+        job.active_work -= 1;
+        job.active_energy.0 -= job_energy;
+        job.active_energy.1 -= user_energy;
+        self.add_energy(perm_id, -job_energy, -user_energy);
+    }
+
+    fn charge(&mut self, perm_id: PermuterId, info: &JobInfo, time_us: f64) {
+        self.sync_owner(perm_id, &info.owner);
+        self.add_energy(
+            perm_id,
+            info.energy_add * time_us,
+            time_us / info.user_priority,
+        );
+    }
+}
+
+/// Hands out work to the job with the least work in flight, ignoring
+/// priorities and users. Mostly useful as a baseline to compare against.
+pub(crate) struct EvenScheduler;
+
+impl Scheduler for EvenScheduler {
+    fn new_queue(&self) -> Box<dyn JobQueue> {
+        Box::new(EvenQueue::default())
+    }
+}
+
+#[derive(Default)]
+struct EvenQueue {
+    /// work in flight, and whether the job is ready, by job
+    jobs: HashMap<PermuterId, (i64, bool)>,
+    /// ready jobs, by work in flight
+    ready: BTreeSet<(i64, PermuterId)>,
+}
+
+impl EvenQueue {
+    fn add_active(&mut self, perm_id: PermuterId, delta: i64) {
+        let (active_work, ready) = self.jobs.get_mut(&perm_id).unwrap();
+        if *ready {
+            self.ready.remove(&(*active_work, perm_id));
+            self.ready.insert((*active_work + delta, perm_id));
+        }
+        *active_work += delta;
+    }
+}
+
+impl JobQueue for EvenQueue {
+    fn add(&mut self, perm_id: PermuterId, _user_id: &UserId) {
+        self.jobs.insert(perm_id, (0, false));
+    }
+
+    fn remove(&mut self, perm_id: PermuterId) {
+        self.set_ready(perm_id, false);
+        self.jobs.remove(&perm_id);
+    }
+
+    fn set_ready(&mut self, perm_id: PermuterId, ready: bool) {
+        let job = self.jobs.get_mut(&perm_id).unwrap();
+        if job.1 {
+            self.ready.remove(&(job.0, perm_id));
+        }
+        if !ready {
+            job.0 = 0;
+        }
+        job.1 = ready;
+        if ready {
+            self.ready.insert((job.0, perm_id));
+        }
+    }
+
+    fn ready_jobs(&self) -> Box<dyn Iterator<Item = PermuterId> + '_> {
+        Box::new(self.ready.iter().map(|&(_, perm_id)| perm_id))
+    }
+
+    fn work_started(&mut self, perm_id: PermuterId, _info: &JobInfo) {
+        self.add_active(perm_id, 1);
+    }
+
+    fn work_finished(&mut self, perm_id: PermuterId) {
+        if self.jobs[&perm_id].0 > 0 {
+            self.add_active(perm_id, -1);
+        }
+    }
+
+    fn charge(&mut self, _perm_id: PermuterId, _info: &JobInfo, _time_us: f64) {}
+}
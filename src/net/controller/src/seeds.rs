@@ -0,0 +1,89 @@
+use serde::Deserialize;
+
+/// A range of seeds that a client lets the controller hand out on its behalf,
+/// instead of sending work items one by one.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SeedSpec {
+    /// seeds are taken from 0..count
+    pub count: u64,
+    /// whether to start over once every seed has been handed out
+    #[serde(default)]
+    pub repeat: bool,
+    /// seed for the order in which seeds are handed out
+    #[serde(default)]
+    pub rng_seed: u64,
+}
+
+/// Hands out the seeds in 0..count in a pseudo-random order, by stepping
+/// through them with a random stride coprime to count. Each pass over the
+/// range uses a new order.
+pub struct SeedGenerator {
+    count: u64,
+    repeat: bool,
+    rng: u64,
+    offset: u64,
+    stride: u64,
+    index: u64,
+}
+
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+impl SeedGenerator {
+    pub fn new(spec: &SeedSpec) -> SeedGenerator {
+        let mut gen = SeedGenerator {
+            count: spec.count,
+            repeat: spec.repeat,
+            rng: spec.rng_seed,
+            offset: 0,
+            stride: 1,
+            index: 0,
+        };
+        gen.shuffle();
+        gen
+    }
+
+    /// splitmix64
+    fn next_random(&mut self) -> u64 {
+        self.rng = self.rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn shuffle(&mut self) {
+        self.index = 0;
+        if self.count <= 1 {
+            return;
+        }
+        self.offset = self.next_random() % self.count;
+        loop {
+            self.stride = 1 + self.next_random() % (self.count - 1);
+            if gcd(self.stride, self.count) == 1 {
+                break;
+            }
+        }
+    }
+}
+
+impl Iterator for SeedGenerator {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        if self.index == self.count {
+            if !self.repeat || self.count == 0 {
+                return None;
+            }
+            self.shuffle();
+        }
+        let seed =
+            (self.offset as u128 + self.index as u128 * self.stride as u128) % self.count as u128;
+        self.index += 1;
+        Some(seed as u64)
+    }
+}
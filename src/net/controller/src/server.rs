@@ -1,36 +1,84 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::iter;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use chrono::Utc;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use tokio::sync::{mpsc, mpsc::error::TrySendError, watch, Notify};
+use tokio::time;
 
-use crate::db::UserId;
+use crate::archive::ResultInfo;
+use crate::db::{Discovery, UserId};
+use crate::images;
+use crate::logging;
 use crate::port::{ReadPort, WritePort};
+use crate::scheduler::{JobInfo, JobQueue, Scheduler};
 use crate::stats;
-use crate::util::SimpleResult;
+use crate::util::{self, SimpleResult};
 use crate::{
-    ConnectedServer, MutableState, PermuterData, PermuterId, PermuterResult, PermuterWork,
-    ServerUpdate, State, HEARTBEAT_TIME,
+    is_disconnect, ConnectedServer, MutableState, Permuter, PermuterData, PermuterId,
+    PermuterResult, PermuterWork, ServerId, ServerUpdate, Session, State, HEARTBEAT_TIME,
 };
 
 const MIN_PERMUTER_VERSION: u32 = 1;
 
-const SERVER_WORK_QUEUE_SIZE: usize = 100;
-const TIME_US_GUESS: f64 = 100_000.0;
-const MIN_OVERHEAD_US: f64 = 100_000.0;
-const MAX_OVERHEAD_FACTOR: i64 = 2;
+pub(crate) const SERVER_WORK_QUEUE_SIZE: usize = 100;
+/// How many ready jobs to try per turn at the global lock, when looking for
+/// one with work to hand out.
+const PICK_BATCH: usize = 8;
 
 #[derive(Debug, Deserialize)]
 pub(crate) struct ConnectServerData {
     min_priority: f64,
     num_cores: f64,
+    /// whether to hand out a resume token for the session
+    #[serde(default)]
+    resumable: bool,
+    /// resume token of an earlier session to pick up the jobs of
+    #[serde(default)]
+    resume: Option<String>,
+    /// jobs the server still has loaded from that session
+    #[serde(default)]
+    loaded: Vec<LoadedJob>,
+    /// whether the server caches blobs, and so wants their hashes instead
+    #[serde(default)]
+    blob_cache: bool,
+    /// what the server can run, such as toolchains, architectures and image
+    /// variants
+    #[serde(default)]
+    tags: BTreeSet<String>,
+    /// names of the docker images the server runs jobs in; servers that
+    /// don't say only run the default one
+    #[serde(default)]
+    docker_images: Option<BTreeSet<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LoadedJob {
+    permuter: PermuterId,
+    hash: String,
+}
+
+/// The jobs a disconnected server had loaded, remembered for a grace period
+/// so that it can resume without loading them again.
+pub(crate) struct SuspendedServer {
+    server_id: UserId,
+    /// hash reported at load time, by permuter
+    jobs: HashMap<PermuterId, String>,
+    since: Instant,
 }
 
 #[derive(Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 enum ServerMessage {
     NeedWork,
+    /// Blobs of a job being added that the server doesn't have cached.
+    NeedBlobs {
+        permuter: PermuterId,
+        hashes: Vec<String>,
+    },
     Update {
         permuter: PermuterId,
         time_us: f64,
@@ -38,27 +86,55 @@ enum ServerMessage {
     },
 }
 
-enum JobState {
+pub(crate) enum JobState {
     Loading,
     Loaded,
     Failed,
 }
 
-struct Job {
-    state: JobState,
-    energy: f64,
-    active_work: i64,
+pub(crate) struct Job {
+    pub(crate) state: JobState,
+    /// hash reported by the server when it finished loading
+    hash: Option<String>,
+    pub(crate) active_work: i64,
 }
 
-struct ServerState {
-    min_priority: f64,
+/// A server's own state, which is locked before the global state when both
+/// are needed, and never after. Its queue is only used with the global lock
+/// released, so that servers don't wait on each other to schedule work.
+pub(crate) struct ServerState {
+    pub(crate) id: ServerId,
+    pub(crate) min_priority: f64,
+    blob_cache: bool,
     /// sum of active_work across all jobs
-    active_work: i64,
+    pub(crate) active_work: i64,
     /// fractional part of how much work should be requested, in [0, 1)
     more_work_acc: f64,
-    jobs: HashMap<PermuterId, Job>,
+    pub(crate) jobs: HashMap<PermuterId, Job>,
+    /// the jobs, ordered by which to work on next
+    pub(crate) queue: Box<dyn JobQueue>,
+}
+
+impl ServerState {
+    pub(crate) fn new(
+        id: ServerId,
+        min_priority: f64,
+        blob_cache: bool,
+        queue: Box<dyn JobQueue>,
+    ) -> ServerState {
+        ServerState {
+            id,
+            min_priority,
+            blob_cache,
+            active_work: 0,
+            more_work_acc: 0.0,
+            jobs: HashMap::new(),
+            queue,
+        }
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn server_read(
     port: &mut ReadPort<'_>,
     who_id: &UserId,
@@ -66,11 +142,16 @@ async fn server_read(
     server_state: &Mutex<ServerState>,
     state: &State,
     more_work_tx: mpsc::Sender<()>,
+    blob_tx: mpsc::UnboundedSender<BlobRequest>,
     new_permuter: &Notify,
 ) -> SimpleResult<()> {
     loop {
         let msg = port.recv().await?;
         let mut msg: ServerMessage = serde_json::from_slice(&msg)?;
+        if let ServerMessage::NeedBlobs { permuter, hashes } = msg {
+            request_blobs(permuter, hashes, server_state, state, &blob_tx)?;
+            continue;
+        }
         if let ServerMessage::Update {
             update:
                 ServerUpdate::Result {
@@ -85,11 +166,13 @@ async fn server_read(
         }
 
         let mut has_new = false;
-        let mut request_work;
+        let mut cpu_time = Vec::new();
+        let mut result = None;
+        let request_work;
 
         {
-            let mut m = state.m.lock().unwrap();
             let mut server_state = server_state.lock().unwrap();
+            let server_state = &mut *server_state;
 
             let mut more_work: f64 = 1.0;
 
@@ -99,82 +182,38 @@ async fn server_read(
                 time_us,
             } = msg
             {
-                // If we get back a message referring to a since-removed
-                // permuter, no need to do anything. Just request one more
-                // piece of work to make up for it.
-                if let Some(job) = server_state.jobs.get_mut(&perm_id) {
-                    if let Some(perm) = m.permuters.get_mut(&perm_id) {
-                        job.energy += perm.energy_add * time_us;
-
-                        match update {
-                            ServerUpdate::InitDone { .. } => {
-                                if !matches!(job.state, JobState::Loading) {
-                                    Err("Got InitDone while not in Loading state")?;
-                                }
-                                job.state = JobState::Loaded;
-                                has_new = true;
-                            }
-                            ServerUpdate::InitFailed { .. } => {
-                                if !matches!(job.state, JobState::Loading) {
-                                    Err("Got InitFailed while not in Loading state")?;
-                                }
-                                job.state = JobState::Failed;
-                            }
-                            ServerUpdate::Disconnect { .. } => {
-                                if !matches!(job.state, JobState::Loaded) {
-                                    Err("Got Disconnect while not in Loaded state")?;
-                                }
-                                job.state = JobState::Failed;
-                                let work = job.active_work;
-                                job.active_work = 0;
-                                server_state.active_work -= work;
-                                more_work = 0.0;
-                            }
-                            ServerUpdate::Result { overhead_us, .. } => {
-                                if !matches!(job.state, JobState::Loaded) {
-                                    Err("Got result while not in Loaded state")?;
-                                }
-                                // If the work item spent less than some given
-                                // amount of time in queues, request more work.
-                                // This ensures we saturate all server cores.
-                                // On the other hand, if it spends too much time
-                                // in queues, it's best if we reduce the amount
-                                // of work.
-                                // We don't need to adjust for time spent on the
-                                // network, because we have backpressure on slow
-                                // writes on both ends, and read continuously.
-                                job.active_work -= 1;
-                                server_state.active_work -= 1;
-                                let min_overhead_us = (time_us + MIN_OVERHEAD_US) as i64;
-                                if overhead_us == 0 {
-                                    // Legacy server, skip this logic.
-                                } else if overhead_us > MAX_OVERHEAD_FACTOR * min_overhead_us {
-                                    more_work = 0.5;
-                                } else if overhead_us < min_overhead_us {
-                                    more_work = 1.5;
-                                }
-                            }
-                        }
-                        perm.send_result(PermuterResult::Result(
-                            who_id.clone(),
-                            who_name.to_string(),
-                            update,
-                        ));
-                    }
-                }
+                let effect = handle_update(
+                    &state.m,
+                    server_state,
+                    state.scheduler.as_ref(),
+                    who_name,
+                    perm_id,
+                    time_us,
+                    update,
+                )?;
+                more_work = effect.more_work;
+                has_new = effect.loaded;
+                cpu_time = effect.cpu_time;
+                result = effect.result;
             }
 
-            more_work += server_state.more_work_acc;
-            request_work = more_work as i32;
-            server_state.more_work_acc = more_work - request_work as f64;
+            let pending_requests = SERVER_WORK_QUEUE_SIZE - more_work_tx.capacity();
+            request_work = work_to_request(server_state, more_work, pending_requests);
+        }
 
-            if request_work == 0
-                && server_state.active_work == 0
-                && more_work_tx.capacity() == SERVER_WORK_QUEUE_SIZE
-            {
-                // Don't request 0 work if it would lead to total starvation.
-                request_work = 1;
-            }
+        for (client, fn_name, time_us) in cpu_time {
+            state
+                .log_stats(stats::Record::CpuTime {
+                    server: who_id.clone(),
+                    client,
+                    fn_name,
+                    time_us,
+                })
+                .await?;
+        }
+
+        if let Some(result) = result {
+            record_result(state, who_id, result).await?;
         }
 
         if has_new {
@@ -197,117 +236,433 @@ async fn server_read(
     }
 }
 
+/// What handling an update from a server led to.
+pub(crate) struct UpdateEffect {
+    /// how much more work to request from the server
+    pub(crate) more_work: f64,
+    /// whether the server finished loading a job
+    pub(crate) loaded: bool,
+    /// CPU time to log, with the client and function it was spent on, split
+    /// between the clients attached to the job
+    pub(crate) cpu_time: Vec<(UserId, String, f64)>,
+    /// a finished work item, to record once however many clients share the job
+    pub(crate) result: Option<WorkResult>,
+}
+
+/// The outcome of a work item, as recorded in stats and the archive.
+pub(crate) struct WorkResult {
+    /// the user who owns the job
+    client: UserId,
+    data: Arc<PermuterData>,
+    outcome: stats::Outcome,
+    score: Option<i64>,
+    hash: Option<String>,
+    /// the compressed source, for results that are worth archiving
+    source: Option<Vec<u8>>,
+    time_us: f64,
+}
+
+impl WorkResult {
+    fn new(perm: &Permuter, update: &ServerUpdate, time_us: f64) -> Option<WorkResult> {
+        let (compressed_source, more_props) = match update {
+            ServerUpdate::Result {
+                compressed_source,
+                more_props,
+                ..
+            } => (compressed_source, more_props),
+            _ => return None,
+        };
+        let score = more_props.get("score").and_then(|score| score.as_i64());
+        let outcome = if compressed_source.is_none() {
+            stats::Outcome::Unhelpful
+        } else if matches!(score, Some(0)) {
+            stats::Outcome::Matched
+        } else {
+            stats::Outcome::Improved
+        };
+        let source = match outcome {
+            stats::Outcome::Unhelpful => None,
+            _ => compressed_source.clone(),
+        };
+        Some(WorkResult {
+            client: perm.client_id.clone(),
+            data: perm.data.clone(),
+            outcome,
+            score,
+            hash: more_props
+                .get("hash")
+                .and_then(|hash| hash.as_str())
+                .map(str::to_string),
+            source,
+            time_us,
+        })
+    }
+}
+
+/// Record a finished work item in metrics, the archive and the stats log.
+async fn record_result(state: &State, who_id: &UserId, result: WorkResult) -> SimpleResult<()> {
+    let WorkResult {
+        client,
+        data,
+        outcome,
+        score,
+        hash,
+        source,
+        time_us,
+    } = result;
+    state.metrics.record_outcome(outcome);
+    if let (stats::Outcome::Matched | stats::Outcome::Improved, Some(score)) = (outcome, score) {
+        if let (Some(archive), Some(source)) = (&state.archive, &source) {
+            let info = ResultInfo {
+                time: Utc::now().timestamp(),
+                fn_name: data.fn_name.clone(),
+                base_hash: data.base_hash().map(str::to_string),
+                score,
+                hash: hash.clone(),
+                client: client.clone(),
+                server: who_id.clone(),
+            };
+            let r = tokio::task::block_in_place(|| archive.store(&info, source));
+            if let Err(e) = r {
+                logging::warn(
+                    "failed to archive result",
+                    &[("error", e.to_string().into())],
+                );
+            }
+        }
+        state
+            .log_stats(stats::Record::Discovery(Discovery {
+                time: Utc::now().timestamp(),
+                fn_name: data.fn_name.clone(),
+                client: client.clone(),
+                server: who_id.clone(),
+                score,
+                hash,
+                time_us,
+            }))
+            .await?;
+    }
+    state
+        .log_stats(stats::Record::WorkDone {
+            server: who_id.clone(),
+            client,
+            fn_name: data.fn_name.clone(),
+            outcome,
+        })
+        .await?;
+    Ok(())
+}
+
+/// Apply an update from a server about one of its jobs, and pass it on to the
+/// job's clients. The global lock is only held while dealing with the
+/// permuter; the server's queue is updated after releasing it.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn handle_update(
+    m_lock: &Mutex<MutableState>,
+    server_state: &mut ServerState,
+    scheduler: &dyn Scheduler,
+    who_name: &str,
+    perm_id: PermuterId,
+    time_us: f64,
+    update: ServerUpdate,
+) -> SimpleResult<UpdateEffect> {
+    let mut effect = UpdateEffect {
+        more_work: 1.0,
+        loaded: false,
+        cpu_time: Vec::new(),
+        result: None,
+    };
+
+    let mut m = m_lock.lock().unwrap();
+    // If we get back a message referring to a since-removed permuter, no need
+    // to do anything. Just request one more piece of work to make up for it.
+    if !server_state.jobs.contains_key(&perm_id) || !m.permuters.contains_key(&perm_id) {
+        return Ok(effect);
+    }
+    let info = JobInfo::new(&m, server_state.id, perm_id);
+    let job = server_state.jobs.get_mut(&perm_id).unwrap();
+    let perm = m.permuters.get_mut(&perm_id).unwrap();
+    if time_us > 0.0 {
+        effect.cpu_time = perm
+            .split_time(time_us)
+            .into_iter()
+            .map(|(client, time_us)| (client, perm.data.fn_name.clone(), time_us))
+            .collect();
+    }
+
+    // Whether the job became ready or stopped being so, and whether a work
+    // item finished, for the server's queue.
+    let (ready, finished) = match update {
+        ServerUpdate::InitDone { ref hash } => {
+            if !matches!(job.state, JobState::Loading) {
+                Err("Got InitDone while not in Loading state")?;
+            }
+            job.state = JobState::Loaded;
+            job.hash = Some(hash.clone());
+            perm.loaded_servers += 1;
+            effect.loaded = true;
+            (Some(true), false)
+        }
+        ServerUpdate::InitFailed { .. } => {
+            if !matches!(job.state, JobState::Loading) {
+                Err("Got InitFailed while not in Loading state")?;
+            }
+            job.state = JobState::Failed;
+            (None, false)
+        }
+        ServerUpdate::Disconnect => {
+            if !matches!(job.state, JobState::Loaded) {
+                Err("Got Disconnect while not in Loaded state")?;
+            }
+            job.state = JobState::Failed;
+            perm.loaded_servers -= 1;
+            server_state.active_work -= job.active_work;
+            job.active_work = 0;
+            effect.more_work = 0.0;
+            (Some(false), false)
+        }
+        ServerUpdate::Result { overhead_us, .. } => {
+            if !matches!(job.state, JobState::Loaded) {
+                Err("Got result while not in Loaded state")?;
+            }
+            perm.results.record();
+            if time_us > 0.0 {
+                perm.record_time(server_state.id, time_us);
+            }
+            job.active_work -= 1;
+            server_state.active_work -= 1;
+            effect.more_work = scheduler.more_work(time_us, overhead_us);
+            effect.result = WorkResult::new(perm, &update, time_us);
+            (None, true)
+        }
+    };
+    perm.send_result(PermuterResult::Result(
+        who_name.to_string(),
+        update,
+        time_us,
+    ));
+    drop(m);
+
+    let queue = &mut server_state.queue;
+    queue.charge(perm_id, &info, time_us);
+    if let Some(ready) = ready {
+        queue.set_ready(perm_id, ready);
+    }
+    if finished {
+        queue.work_finished(perm_id);
+    }
+    Ok(effect)
+}
+
+/// Turn an amount of work to request into a whole number of requests,
+/// carrying over the fractional part, given how many requests are still
+/// waiting to be acted on.
+pub(crate) fn work_to_request(
+    server_state: &mut ServerState,
+    more_work: f64,
+    pending_requests: usize,
+) -> usize {
+    let more_work = more_work + server_state.more_work_acc;
+    let mut request_work = more_work as usize;
+    server_state.more_work_acc = more_work - request_work as f64;
+
+    if request_work == 0 && server_state.active_work == 0 && pending_requests == 0 {
+        // Don't request 0 work if it would lead to total starvation.
+        request_work = 1;
+    }
+    request_work
+}
+
+/// Pass on a server's request for blobs to the writer. Requests for jobs
+/// that have since been removed are ignored.
+fn request_blobs(
+    perm_id: PermuterId,
+    hashes: Vec<String>,
+    server_state: &Mutex<ServerState>,
+    state: &State,
+    blob_tx: &mpsc::UnboundedSender<BlobRequest>,
+) -> SimpleResult<()> {
+    let server_state = server_state.lock().unwrap();
+    let m = state.m.lock().unwrap();
+    let job = match server_state.jobs.get(&perm_id) {
+        Some(job) => job,
+        None => return Ok(()),
+    };
+    if !matches!(job.state, JobState::Loading) {
+        Err("Got NeedBlobs while not in Loading state")?;
+    }
+    let data = match m.permuters.get(&perm_id) {
+        Some(perm) => perm.data.clone(),
+        None => return Ok(()),
+    };
+    if hashes.iter().any(|hash| data.blob(hash).is_none()) {
+        Err("Requested unknown blob")?;
+    }
+    blob_tx
+        .send(BlobRequest {
+            permuter: perm_id,
+            data,
+            hashes,
+        })
+        .map_err(|_| ())
+        .expect("writer must not close except on error");
+    Ok(())
+}
+
+/// Blobs to send to a server that is missing them.
+struct BlobRequest {
+    permuter: PermuterId,
+    data: Arc<PermuterData>,
+    hashes: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct BlobHashes {
+    source: String,
+    target_o_bin: String,
+}
+
 #[derive(Serialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
-enum ToSend {
+pub(crate) enum ToSend {
     Work(PermuterWork),
     Add {
         client_id: UserId,
         client_name: String,
         data: Arc<PermuterData>,
+        /// If set, the blobs are not sent along, and the server asks for the
+        /// ones it doesn't have.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        blobs: Option<BlobHashes>,
     },
     Remove,
 }
 
 #[derive(Serialize)]
-struct OutMessage {
-    permuter: PermuterId,
+pub(crate) struct OutMessage {
+    pub(crate) permuter: PermuterId,
     #[serde(flatten)]
-    to_send: ToSend,
+    pub(crate) to_send: ToSend,
 }
 
-fn try_next_work_message(
-    m: &mut MutableState,
+/// What to send to a server next, decided with the global lock held, and
+/// applied to the server's queue after releasing it.
+enum Next {
+    Add(OutMessage, UserId),
+    Remove(PermuterId),
+    Work(PermuterId, PermuterWork, JobInfo),
+}
+
+/// Pick the next message to send to a server, if there is anything to do.
+/// New jobs are sent first, then removals, and then work.
+pub(crate) fn try_next_work_message(
+    m_lock: &Mutex<MutableState>,
     server_state: &mut ServerState,
 ) -> Option<OutMessage> {
-    let mut skip = HashSet::new();
-    loop {
-        // If possible, send a new permuter.
-        if let Some((&perm_id, perm)) = m
-            .permuters
-            .iter()
-            .find(|(&perm_id, _)| !server_state.jobs.contains_key(&perm_id))
-        {
+    let next = choose_next(m_lock, server_state)?;
+    let queue = &mut server_state.queue;
+    Some(match next {
+        Next::Add(message, owner) => {
             server_state.jobs.insert(
-                perm_id,
+                message.permuter,
                 Job {
                     state: JobState::Loading,
-                    energy: 0.0,
+                    hash: None,
                     active_work: 0,
                 },
             );
-            return Some(OutMessage {
+            queue.add(message.permuter, &owner);
+            message
+        }
+        Next::Remove(perm_id) => {
+            let job = server_state.jobs.remove(&perm_id).unwrap();
+            server_state.active_work -= job.active_work;
+            queue.remove(perm_id);
+            OutMessage {
                 permuter: perm_id,
-                to_send: ToSend::Add {
-                    client_id: perm.client_id.clone(),
-                    client_name: perm.client_name.clone(),
-                    data: perm.data.clone(),
-                },
-            });
+                to_send: ToSend::Remove,
+            }
         }
-
-        // If none, find an existing one to work on, or to remove.
-        let mut best_cost = 0.0;
-        let mut best: Option<(PermuterId, &mut Job)> = None;
-        let min_priority = server_state.min_priority;
-        for (&perm_id, job) in server_state.jobs.iter_mut() {
-            if let Some(perm) = m.permuters.get(&perm_id) {
-                let energy =
-                    job.energy + (job.active_work as f64) * perm.energy_add * TIME_US_GUESS;
-                if matches!(job.state, JobState::Loaded)
-                    && !skip.contains(&perm_id)
-                    && perm.priority >= min_priority
-                    && (best.is_none() || energy < best_cost)
-                {
-                    best_cost = energy;
-                    best = Some((perm_id, job));
-                }
-            } else {
-                server_state.active_work -= job.active_work;
-                server_state.jobs.remove(&perm_id);
-                return Some(OutMessage {
-                    permuter: perm_id,
-                    to_send: ToSend::Remove,
-                });
+        Next::Work(perm_id, work, info) => {
+            server_state.jobs.get_mut(&perm_id).unwrap().active_work += 1;
+            server_state.active_work += 1;
+            queue.work_started(perm_id, &info);
+            OutMessage {
+                permuter: perm_id,
+                to_send: ToSend::Work(work),
             }
         }
+    })
+}
 
-        let (perm_id, job) = match best {
-            None => return None,
-            Some(tup) => tup,
-        };
+fn choose_next(m_lock: &Mutex<MutableState>, server_state: &ServerState) -> Option<Next> {
+    // Ready jobs are looked at a few at a time, so that the global lock isn't
+    // held while going through the queue.
+    let mut ready_jobs = server_state.queue.ready_jobs();
+    let mut batch: Vec<PermuterId> = ready_jobs.by_ref().take(PICK_BATCH).collect();
+    let mut m = m_lock.lock().unwrap();
+    let server = m.servers.get_mut(server_state.id).unwrap();
 
-        let perm = m.permuters.get_mut(&perm_id).unwrap();
-        let work = match perm.work_queue.pop_front() {
-            None => {
-                // Chosen permuter is out of work. Ask it for more, and try
-                // again without it as a candidate. When the queue becomes
-                // non-empty again all sleeping writers will be notified.
-                perm.send_result(PermuterResult::NeedWork);
-                skip.insert(perm_id);
-                continue;
-            }
-            Some(work) => work,
+    // If possible, send a new permuter, oldest first.
+    if let Some(perm_id) = server.pending_adds.pop_first() {
+        let perm = &m.permuters[&perm_id];
+        let message = OutMessage {
+            permuter: perm_id,
+            to_send: ToSend::Add {
+                client_id: perm.client_id.clone(),
+                client_name: perm.client_name.clone(),
+                data: perm.data.clone(),
+                blobs: server_state.blob_cache.then(|| BlobHashes {
+                    source: perm.data.source_hash.clone(),
+                    target_o_bin: perm.data.target_o_bin_hash.clone(),
+                }),
+            },
         };
+        return Some(Next::Add(message, perm.client_id.clone()));
+    }
 
-        perm.semaphore.release();
-
-        let min_energy = job.energy;
-        job.active_work += 1;
-        server_state.active_work += 1;
+    // If none, see if there is one to remove.
+    while let Some(perm_id) = server.pending_removes.pop_first() {
+        if server_state.jobs.contains_key(&perm_id) {
+            return Some(Next::Remove(perm_id));
+        }
+    }
 
-        // Adjust energies to be around zero, to avoid problems with float
-        // imprecision, and to ensure that new permuters that come in with
-        // energy zero will fit the schedule.
-        for job in server_state.jobs.values_mut() {
-            job.energy -= min_energy;
+    // Otherwise, find one to work on.
+    loop {
+        for perm_id in batch {
+            if let Some(work) = take_work(&mut m, server_state, perm_id) {
+                let info = JobInfo::new(&m, server_state.id, perm_id);
+                return Some(Next::Work(perm_id, work, info));
+            }
+        }
+        drop(m);
+        batch = ready_jobs.by_ref().take(PICK_BATCH).collect();
+        if batch.is_empty() {
+            return None;
         }
+        m = m_lock.lock().unwrap();
+    }
+}
 
-        return Some(OutMessage {
-            permuter: perm_id,
-            to_send: ToSend::Work(work),
-        });
+/// Take a work item from a permuter, if it can run on the server.
+fn take_work(
+    m: &mut MutableState,
+    server_state: &ServerState,
+    perm_id: PermuterId,
+) -> Option<PermuterWork> {
+    let perm = match m.permuters.get_mut(&perm_id) {
+        Some(perm) if perm.priority >= server_state.min_priority => perm,
+        _ => return None,
+    };
+    let work = perm.pop_work();
+    if work.is_none() {
+        // Chosen permuter is out of work. Ask it for more, and try the next
+        // one. When the queue becomes non-empty again all sleeping writers
+        // will be notified.
+        perm.send_result(PermuterResult::NeedWork);
     }
+    work
 }
 
 async fn next_work_message(
@@ -315,32 +670,26 @@ async fn next_work_message(
     state: &State,
     new_permuter: &Notify,
 ) -> OutMessage {
-    let mut wait_for = None;
     loop {
-        if let Some(waiter) = wait_for {
-            waiter.await;
-        }
-        let mut m = state.m.lock().unwrap();
-        let mut server_state = server_state.lock().unwrap();
-        match try_next_work_message(&mut m, &mut server_state) {
+        // Register to be notified when something happens before looking, so
+        // that nothing that happens in the meantime is missed.
+        let n1 = state.new_work_notification.notified();
+        let n2 = new_permuter.notified();
+        let message = try_next_work_message(&state.m, &mut server_state.lock().unwrap());
+        match message {
             Some(message) => return message,
             None => {
-                // Nothing to work on! Register to be notified when something
-                // happens (while the lock is still held) and go to sleep.
-                let n1 = state.new_work_notification.notified();
-                let n2 = new_permuter.notified();
-                wait_for = Some(async move {
-                    tokio::select! {
-                        () = n1 => {}
-                        () = n2 => {}
-                    }
-                });
+                // Nothing to work on! Go to sleep.
+                tokio::select! {
+                    () = n1 => {}
+                    () = n2 => {}
+                }
             }
         }
     }
 }
 
-fn requires_response(work: &OutMessage) -> bool {
+pub(crate) fn requires_response(work: &OutMessage) -> bool {
     match work.to_send {
         ToSend::Work { .. } => true,
         ToSend::Add { .. } => true,
@@ -381,18 +730,49 @@ async fn send_heartbeat(port: &mut WritePort<'_>) -> SimpleResult<()> {
     .await
 }
 
-async fn send_work(port: &mut WritePort<'_>, work: &OutMessage) -> SimpleResult<()> {
+async fn send_work(port: &mut WritePort<'_>, work: &OutMessage, state: &State) -> SimpleResult<()> {
     port.send_json(&work).await?;
-    if let ToSend::Add { ref data, .. } = work.to_send {
-        port.send(&data.compressed_source).await?;
-        port.send(&data.compressed_target_o_bin).await?;
+    if let ToSend::Add {
+        ref data,
+        ref blobs,
+        ..
+    } = work.to_send
+    {
+        let size = data.compressed_source.len() + data.compressed_target_o_bin.len();
+        state.metrics.record_blobs_needed(size);
+        if blobs.is_none() {
+            port.send(&data.compressed_source).await?;
+            port.send(&data.compressed_target_o_bin).await?;
+            state.metrics.record_blobs_sent(size);
+        }
+    }
+    Ok(())
+}
+
+async fn send_blobs(
+    port: &mut WritePort<'_>,
+    req: &BlobRequest,
+    state: &State,
+) -> SimpleResult<()> {
+    port.send_json(&json!({
+        "type": "blobs",
+        "permuter": req.permuter,
+        "hashes": &req.hashes,
+    }))
+    .await?;
+    for hash in &req.hashes {
+        let blob = req.data.blob(hash).expect("checked by reader");
+        port.send(blob).await?;
+        state.metrics.record_blobs_sent(blob.len());
     }
     Ok(())
 }
 
 async fn server_write(
     port: &mut WritePort<'_>,
+    state: &State,
     mut next_message_rx: mpsc::Receiver<OutMessage>,
+    mut blob_rx: mpsc::UnboundedReceiver<BlobRequest>,
     mut heartbeat_rx: watch::Receiver<()>,
     wrote_message: &Notify,
 ) -> SimpleResult<()> {
@@ -400,9 +780,13 @@ async fn server_write(
         tokio::select! {
             work = next_message_rx.recv() => {
                 let work = work.expect("chooser must not close except on error");
-                send_work(port, &work).await?;
+                send_work(port, &work, state).await?;
                 wrote_message.notify_one();
             }
+            req = blob_rx.recv() => {
+                let req = req.expect("reader must not close except on error");
+                send_blobs(port, &req, state).await?;
+            }
             res = heartbeat_rx.changed() => {
                 res.expect("heartbeat thread panicked");
                 send_heartbeat(port).await?;
@@ -411,89 +795,273 @@ async fn server_write(
     }
 }
 
+fn take_suspended(state: &State, token: &str, who_id: &UserId) -> Option<SuspendedServer> {
+    let mut m = state.m.lock().unwrap();
+    if m.suspended_servers.get(token)?.server_id != *who_id {
+        return None;
+    }
+    m.suspended_servers.remove(token)
+}
+
+/// Remember the loaded jobs of a disconnected server until the grace period
+/// runs out, unless the server resumes before then.
+fn suspend(state: &'static State, token: String, suspended: SuspendedServer, grace: Duration) {
+    let since = suspended.since;
+    state
+        .m
+        .lock()
+        .unwrap()
+        .suspended_servers
+        .insert(token.clone(), suspended);
+    tokio::spawn(async move {
+        time::sleep(grace).await;
+        let mut m = state.m.lock().unwrap();
+        // The server may have resumed, and perhaps been suspended again.
+        if m.suspended_servers
+            .get(&token)
+            .is_some_and(|suspended| suspended.since == since)
+        {
+            m.suspended_servers.remove(&token);
+        }
+    });
+}
+
+/// Drop the suspended sessions of a user.
+pub(crate) fn drop_suspended(m: &mut MutableState, user_id: &UserId) {
+    m.suspended_servers
+        .retain(|_, suspended| suspended.server_id != *user_id);
+}
+
+/// Restore the jobs that a resuming server still has loaded, if they were
+/// loaded by the session it resumes, with the same hash, and are still
+/// running. Returns the ids of the restored jobs; the server should drop
+/// the rest.
+fn restore_jobs(
+    m: &mut MutableState,
+    server_state: &mut ServerState,
+    suspended: &SuspendedServer,
+    loaded: &[LoadedJob],
+    who_name: &str,
+) -> Vec<PermuterId> {
+    let mut restored = Vec::new();
+    for job in loaded {
+        if suspended.jobs.get(&job.permuter) != Some(&job.hash)
+            || server_state.jobs.contains_key(&job.permuter)
+        {
+            continue;
+        }
+        // Jobs the server can run and doesn't have yet are pending.
+        let server = m.servers.get_mut(server_state.id).unwrap();
+        if !server.pending_adds.remove(&job.permuter) {
+            continue;
+        }
+        let perm = m.permuters.get_mut(&job.permuter).unwrap();
+        server_state.jobs.insert(
+            job.permuter,
+            Job {
+                state: JobState::Loaded,
+                hash: Some(job.hash.clone()),
+                active_work: 0,
+            },
+        );
+        server_state.queue.add(job.permuter, &perm.client_id);
+        server_state.queue.set_ready(job.permuter, true);
+        perm.loaded_servers += 1;
+        // Let the client know the server is back, as if it had just loaded
+        // the job.
+        perm.send_result(PermuterResult::Result(
+            who_name.to_string(),
+            ServerUpdate::InitDone {
+                hash: job.hash.clone(),
+            },
+            0.0,
+        ));
+        restored.push(job.permuter);
+    }
+    restored
+}
+
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn handle_connect_server<'a>(
     mut read_port: ReadPort<'a>,
     mut write_port: WritePort<'a>,
     who_id: UserId,
     who_name: &str,
     permuter_version: u32,
-    state: &State,
+    state: &'static State,
+    session: &Session<'_>,
     data: ConnectServerData,
 ) -> SimpleResult<()> {
     if permuter_version < MIN_PERMUTER_VERSION {
         Err("Permuter version too old!")?;
     }
 
-    eprintln!(
-        "[{}] start server ({}, {})",
-        who_name, data.min_priority, data.num_cores
+    let docker_images = data
+        .docker_images
+        .clone()
+        .unwrap_or_else(|| iter::once(images::DEFAULT_IMAGE.to_string()).collect());
+    let mut image_refs = BTreeMap::new();
+    for name in &docker_images {
+        match state.docker_images.get(name, permuter_version) {
+            Ok(image) => {
+                image_refs.insert(name.clone(), image.reference());
+            }
+            Err(e) => {
+                write_port.send_error(&e).await?;
+                Err(e)?;
+            }
+        }
+    }
+
+    logging::info(
+        "start server",
+        &[
+            ("min_priority", data.min_priority.into()),
+            ("num_cores", data.num_cores.into()),
+            ("tags", json!(data.tags)),
+            ("docker_images", json!(docker_images)),
+        ],
     );
 
-    write_port
-        .send_json(&json!({
-            "docker_image": &state.docker_image,
-            "heartbeat_interval": HEARTBEAT_TIME.as_secs(),
-        }))
-        .await?;
+    let resumed = data
+        .resume
+        .as_deref()
+        .and_then(|token| take_suspended(state, token, &who_id));
+    let token = match resumed {
+        Some(_) => data.resume.clone(),
+        None if data.resumable && state.server_resume_grace.is_some() => Some(util::resume_token()),
+        None => None,
+    };
+
+    let (id, mut server_state, restored) = {
+        let mut m = state.m.lock().unwrap();
+        let id = m.add_server(ConnectedServer {
+            owner_name: who_name.to_string(),
+            min_priority: data.min_priority,
+            num_cores: data.num_cores,
+            tags: data.tags.clone(),
+            docker_images,
+            pending_adds: BTreeSet::new(),
+            pending_removes: BTreeSet::new(),
+        });
+        let mut server_state = ServerState::new(
+            id,
+            data.min_priority,
+            data.blob_cache,
+            state.scheduler.new_queue(),
+        );
+        let restored = resumed.map(|suspended| {
+            restore_jobs(
+                &mut m,
+                &mut server_state,
+                &suspended,
+                &data.loaded,
+                who_name,
+            )
+        });
+        (id, Mutex::new(server_state), restored)
+    };
+    if let Some(ref restored) = restored {
+        logging::info("resume server", &[("restored", restored.len().into())]);
+    }
+
+    let mut reply = json!({
+        "docker_image": state.docker_images.default_reference(),
+        "heartbeat_interval": HEARTBEAT_TIME.as_secs(),
+    });
+    if data.docker_images.is_some() {
+        reply["docker_images"] = json!(image_refs);
+    }
+    if let Some(ref token) = token {
+        reply["resume_token"] = token.as_str().into();
+    }
+    if data.resume.is_some() {
+        reply["resumed"] = restored.unwrap_or_default().into();
+    }
+    // The server is registered from here on, so errors need to go through
+    // the cleanup below.
+    let r = write_port.send_json(&reply).await;
 
     let (more_work_tx, more_work_rx) = mpsc::channel(SERVER_WORK_QUEUE_SIZE);
     let (next_message_tx, next_message_rx) = mpsc::channel(1);
+    let (blob_tx, blob_rx) = mpsc::unbounded_channel();
     let wrote_message = Notify::new();
     let new_permuter = Notify::new();
 
-    let mut server_state = Mutex::new(ServerState {
-        min_priority: data.min_priority,
-        active_work: 0,
-        more_work_acc: 0.0,
-        jobs: HashMap::new(),
-    });
-
-    let id = state.m.lock().unwrap().servers.insert(ConnectedServer {
-        min_priority: data.min_priority,
-        num_cores: data.num_cores,
-    });
-
-    let r = tokio::try_join!(
-        server_read(
-            &mut read_port,
-            &who_id,
-            who_name,
-            &server_state,
-            state,
-            more_work_tx,
-            &new_permuter,
-        ),
-        server_choose_work(
-            &server_state,
-            state,
-            more_work_rx,
-            next_message_tx,
-            &wrote_message,
-            &new_permuter,
-        ),
-        server_write(
-            &mut write_port,
-            next_message_rx,
-            state.heartbeat_rx.clone(),
-            &wrote_message,
+    let r = async {
+        r?;
+        tokio::try_join!(
+            server_read(
+                &mut read_port,
+                &who_id,
+                who_name,
+                &server_state,
+                state,
+                more_work_tx,
+                blob_tx,
+                &new_permuter,
+            ),
+            server_choose_work(
+                &server_state,
+                state,
+                more_work_rx,
+                next_message_tx,
+                &wrote_message,
+                &new_permuter,
+            ),
+            server_write(
+                &mut write_port,
+                state,
+                next_message_rx,
+                blob_rx,
+                state.heartbeat_rx.clone(),
+                &wrote_message,
+            ),
+            session.kicked()
         )
-    );
+    }
+    .await;
 
     {
         let mut m = state.m.lock().unwrap();
-        for (&perm_id, job) in &server_state.get_mut().unwrap().jobs {
+        let jobs = &server_state.get_mut().unwrap().jobs;
+        for (&perm_id, job) in jobs {
+            let perm = match m.permuters.get_mut(&perm_id) {
+                Some(perm) => perm,
+                None => continue,
+            };
+            perm.server_time_us.remove(&id);
             if let JobState::Loaded = job.state {
-                if let Some(perm) = m.permuters.get_mut(&perm_id) {
-                    perm.send_result(PermuterResult::Result(
-                        who_id.clone(),
-                        who_name.to_string(),
-                        ServerUpdate::Disconnect,
-                    ));
-                }
+                perm.loaded_servers -= 1;
+                perm.send_result(PermuterResult::Result(
+                    who_name.to_string(),
+                    ServerUpdate::Disconnect,
+                    0.0,
+                ));
             }
         }
 
         m.servers.remove(id);
+        drop(m);
+
+        // If the connection dropped, remember what the server had loaded so
+        // that it can resume.
+        match (token, state.server_resume_grace) {
+            (Some(token), Some(grace)) if matches!(&r, Err(e) if is_disconnect(&**e)) => {
+                logging::info("suspend server", &[("grace_secs", grace.as_secs().into())]);
+                let suspended = SuspendedServer {
+                    server_id: who_id,
+                    jobs: jobs
+                        .iter()
+                        .filter(|(_, job)| matches!(job.state, JobState::Loaded))
+                        .filter_map(|(&perm_id, job)| Some((perm_id, job.hash.clone()?)))
+                        .collect(),
+                    since: Instant::now(),
+                };
+                suspend(state, token, suspended, grace);
+            }
+            _ => {}
+        }
     }
     r?;
     Ok(())
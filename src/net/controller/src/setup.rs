@@ -1,24 +1,15 @@
 use std::collections::HashMap;
 use std::default::Default;
-use std::fs::OpenOptions;
 
 use sodiumoxide::crypto::sign;
 use sodiumoxide::randombytes::randombytes;
 
-use crate::db::{User, UserId, DB};
+use crate::db::{Role, User, UserId, DB};
+use crate::storage;
 use crate::util::SimpleResult;
 use crate::SetupOpts;
 
 pub(crate) fn run_setup(opts: SetupOpts) -> SimpleResult<()> {
-    let db_file = OpenOptions::new()
-        .write(true)
-        .create_new(true)
-        .open(&opts.db)
-        .unwrap_or_else(|e| {
-            eprintln!("Cannot create database file {}: {}. Aborting.", &opts.db, e);
-            std::process::exit(1);
-        });
-
     let server_seed = sign::Seed::from_slice(&randombytes(32)).unwrap();
     let client_seed = sign::Seed::from_slice(&randombytes(32)).unwrap();
 
@@ -28,18 +19,21 @@ pub(crate) fn run_setup(opts: SetupOpts) -> SimpleResult<()> {
     let root_user = User {
         trusted_by: None,
         name: "root".into(),
+        roles: Role::ALL.iter().copied().collect(),
+        limits: Default::default(),
+        cpu_usage: Default::default(),
+        credit: Default::default(),
         client_stats: Default::default(),
         server_stats: Default::default(),
     };
     let mut users_map: HashMap<UserId, User> = HashMap::new();
     users_map.insert(UserId::from_pubkey(&client_pub_key), root_user);
-    let db = DB {
-        users: users_map,
-        func_stats: HashMap::new(),
-        total_stats: Default::default(),
-    };
+    let db = DB::new(users_map);
 
-    serde_json::to_writer(&db_file, &db)?;
+    storage::create(&opts.db, &db).unwrap_or_else(|e| {
+        eprintln!("Cannot create database file {}: {}. Aborting.", &opts.db, e);
+        std::process::exit(1);
+    });
 
     println!(
         "Setup successful!\n\n\
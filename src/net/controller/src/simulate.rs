@@ -0,0 +1,791 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeSet, BinaryHeap, HashMap, VecDeque};
+use std::fs;
+use std::iter;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::hash::sha256;
+use tokio::sync::mpsc;
+
+use crate::db::UserId;
+use crate::images;
+use crate::scheduler::{Scheduler, SchedulerKind};
+use crate::seeds::{SeedGenerator, SeedSpec};
+use crate::server::{self, ServerState, ToSend, SERVER_WORK_QUEUE_SIZE};
+use crate::util::{FutureExt, SimpleResult};
+use crate::{
+    ConnectedServer, MutableState, Permuter, PermuterData, PermuterId, PermuterResult,
+    ServerUpdate, SimulateOpts,
+};
+
+/// A synthetic fleet of servers and clients to simulate.
+#[derive(Deserialize)]
+struct Scenario {
+    /// length of the simulation, in seconds of virtual time
+    duration_secs: f64,
+    /// one-way network latency between the controller and servers
+    #[serde(default)]
+    latency_ms: f64,
+    #[serde(default)]
+    servers: Vec<ServerSpec>,
+    #[serde(default)]
+    clients: Vec<ClientSpec>,
+}
+
+#[derive(Deserialize)]
+struct ServerSpec {
+    /// number of identical servers
+    #[serde(default = "one")]
+    count: usize,
+    cores: usize,
+    /// how much faster than a reference machine the server runs iterations
+    #[serde(default = "one_f64")]
+    speed: f64,
+    #[serde(default)]
+    min_priority: f64,
+}
+
+#[derive(Deserialize)]
+struct ClientSpec {
+    user: String,
+    /// number of identical clients, each with a job of its own
+    #[serde(default = "one")]
+    count: usize,
+    #[serde(default = "one_f64")]
+    priority: f64,
+    /// time an iteration takes on a reference machine
+    iteration_ms: f64,
+    /// time it takes a server to load the job, on a reference machine
+    /// (default: iteration_ms)
+    load_ms: Option<f64>,
+    #[serde(default)]
+    start_secs: f64,
+    stop_secs: Option<f64>,
+}
+
+fn one() -> usize {
+    1
+}
+
+fn one_f64() -> f64 {
+    1.0
+}
+
+fn to_us(ms: f64) -> u64 {
+    (ms * 1000.0).round() as u64
+}
+
+enum Event {
+    ClientStart(usize),
+    ClientStop(usize),
+    /// A message from the controller reaches a server.
+    Deliver {
+        server: usize,
+        perm_id: PermuterId,
+        work: bool,
+        sent_at: u64,
+    },
+    LoadDone {
+        server: usize,
+        perm_id: PermuterId,
+        time_us: u64,
+    },
+    IterationDone {
+        server: usize,
+        perm_id: PermuterId,
+        time_us: u64,
+        received_at: u64,
+        sent_at: u64,
+    },
+    /// An update from a server reaches the controller.
+    Update {
+        server: usize,
+        perm_id: PermuterId,
+        time_us: u64,
+        /// for results, how long the work item spent in queues on the
+        /// server, and when the controller sent it
+        result: Option<(u64, u64)>,
+    },
+}
+
+struct Scheduled {
+    time: u64,
+    seq: u64,
+    event: Event,
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Scheduled) -> bool {
+        (self.time, self.seq) == (other.time, other.seq)
+    }
+}
+
+impl Eq for Scheduled {}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Scheduled) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Scheduled) -> Ordering {
+        (self.time, self.seq).cmp(&(other.time, other.seq))
+    }
+}
+
+struct SimServer {
+    name: String,
+    state: ServerState,
+    cores: usize,
+    speed: f64,
+    running: usize,
+    /// work items waiting for a core: job, time received, time sent
+    queue: VecDeque<(PermuterId, u64, u64)>,
+    /// requests for more work from the reader that the chooser hasn't used
+    credits: usize,
+    /// whether the chooser is waiting for a credit before picking more work
+    awaiting_credit: bool,
+}
+
+struct SimClient {
+    user_id: UserId,
+    iteration_us: u64,
+    load_us: u64,
+    running: Option<(PermuterId, u64, mpsc::UnboundedReceiver<PermuterResult>)>,
+    iterations: u64,
+    cpu_us: f64,
+    latency_us: Vec<u64>,
+}
+
+#[derive(Serialize)]
+struct ClientReport {
+    user: String,
+    priority: f64,
+    iterations: u64,
+    /// share of all CPU time spent on clients
+    cpu_share: f64,
+    mean_latency_ms: f64,
+}
+
+#[derive(Serialize)]
+struct UserReport {
+    user: String,
+    priority: f64,
+    cpu_share: f64,
+    /// share of CPU time the user would get if time were divided by priority
+    fair_share: f64,
+}
+
+#[derive(Serialize)]
+struct Report {
+    scheduler: SchedulerKind,
+    /// busy core time over available core time
+    utilization: f64,
+    /// Jain's index of CPU time per user, weighted by priority: 1 if every
+    /// user gets a share in proportion to their priority, down to 1/users
+    fairness: f64,
+    /// time from the controller sending a work item to receiving its result
+    mean_latency_ms: f64,
+    p95_latency_ms: f64,
+    iterations: u64,
+    events: u64,
+    clients: Vec<ClientReport>,
+    users: Vec<UserReport>,
+}
+
+struct Simulation<'a> {
+    scenario: &'a Scenario,
+    scheduler: &'a dyn Scheduler,
+    m: Mutex<MutableState>,
+    servers: Vec<SimServer>,
+    clients: Vec<SimClient>,
+    client_specs: Vec<&'a ClientSpec>,
+    client_by_perm: HashMap<PermuterId, usize>,
+    events: BinaryHeap<Reverse<Scheduled>>,
+    next_seq: u64,
+    now: u64,
+    latency_us: u64,
+    busy_us: u64,
+    event_count: u64,
+}
+
+fn user_id(name: &str) -> UserId {
+    UserId::from_hex(&hex::encode(sha256::hash(name.as_bytes()))).unwrap()
+}
+
+impl<'a> Simulation<'a> {
+    fn new(scenario: &'a Scenario, scheduler: &'a dyn Scheduler) -> Simulation<'a> {
+        let mut m = MutableState::new();
+        let mut servers = Vec::new();
+        for spec in &scenario.servers {
+            for _ in 0..spec.count {
+                let name = format!("server{}", servers.len());
+                let id = m.add_server(ConnectedServer {
+                    owner_name: name.clone(),
+                    min_priority: spec.min_priority,
+                    num_cores: spec.cores as f64,
+                    tags: BTreeSet::new(),
+                    docker_images: iter::once(images::DEFAULT_IMAGE.to_string()).collect(),
+                    pending_adds: BTreeSet::new(),
+                    pending_removes: BTreeSet::new(),
+                });
+                servers.push(SimServer {
+                    name,
+                    state: ServerState::new(id, spec.min_priority, false, scheduler.new_queue()),
+                    cores: spec.cores,
+                    speed: spec.speed,
+                    running: 0,
+                    queue: VecDeque::new(),
+                    credits: 0,
+                    awaiting_credit: false,
+                });
+            }
+        }
+
+        let mut sim = Simulation {
+            scenario,
+            scheduler,
+            m: Mutex::new(m),
+            servers,
+            clients: Vec::new(),
+            client_specs: Vec::new(),
+            client_by_perm: HashMap::new(),
+            events: BinaryHeap::new(),
+            next_seq: 0,
+            now: 0,
+            latency_us: to_us(scenario.latency_ms),
+            busy_us: 0,
+            event_count: 0,
+        };
+        for spec in &scenario.clients {
+            for _ in 0..spec.count {
+                let index = sim.clients.len();
+                sim.clients.push(SimClient {
+                    user_id: user_id(&spec.user),
+                    iteration_us: to_us(spec.iteration_ms),
+                    load_us: to_us(spec.load_ms.unwrap_or(spec.iteration_ms)),
+                    running: None,
+                    iterations: 0,
+                    cpu_us: 0.0,
+                    latency_us: Vec::new(),
+                });
+                sim.client_specs.push(spec);
+                sim.schedule(to_us(spec.start_secs * 1000.0), Event::ClientStart(index));
+                if let Some(stop_secs) = spec.stop_secs {
+                    sim.schedule(to_us(stop_secs * 1000.0), Event::ClientStop(index));
+                }
+            }
+        }
+        sim
+    }
+
+    fn schedule(&mut self, time: u64, event: Event) {
+        self.events.push(Reverse(Scheduled {
+            time,
+            seq: self.next_seq,
+            event,
+        }));
+        self.next_seq += 1;
+    }
+
+    async fn run(&mut self) -> SimpleResult<()> {
+        let end = to_us(self.scenario.duration_secs * 1000.0);
+        while let Some(Reverse(scheduled)) = self.events.pop() {
+            if scheduled.time > end {
+                break;
+            }
+            self.now = scheduled.time;
+            self.event_count += 1;
+            // Updates are what send clients results, so only the client
+            // whose job got one needs to receive them.
+            let updated = match scheduled.event {
+                Event::Update { perm_id, .. } => self.client_by_perm.get(&perm_id).copied(),
+                _ => None,
+            };
+            self.handle(scheduled.event)?;
+            if let Some(index) = updated {
+                self.drain_results(index).await;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, event: Event) -> SimpleResult<()> {
+        match event {
+            Event::ClientStart(index) => {
+                self.start_client(index);
+                self.pump_all();
+            }
+            Event::ClientStop(index) => {
+                if let Some((perm_id, attachment_id, _)) = self.clients[index].running.take() {
+                    self.m
+                        .get_mut()
+                        .unwrap()
+                        .detach_client(perm_id, attachment_id);
+                }
+                self.pump_all();
+            }
+            Event::Deliver {
+                server,
+                perm_id,
+                work,
+                sent_at,
+            } => {
+                if work {
+                    self.servers[server]
+                        .queue
+                        .push_back((perm_id, self.now, sent_at));
+                    self.start_iterations(server);
+                } else {
+                    let index = self.client_by_perm[&perm_id];
+                    let time_us =
+                        (self.clients[index].load_us as f64 / self.servers[server].speed) as u64;
+                    self.schedule(
+                        self.now + time_us,
+                        Event::LoadDone {
+                            server,
+                            perm_id,
+                            time_us,
+                        },
+                    );
+                }
+            }
+            Event::LoadDone {
+                server,
+                perm_id,
+                time_us,
+            } => {
+                self.schedule(
+                    self.now + self.latency_us,
+                    Event::Update {
+                        server,
+                        perm_id,
+                        time_us,
+                        result: None,
+                    },
+                );
+            }
+            Event::IterationDone {
+                server,
+                perm_id,
+                time_us,
+                received_at,
+                sent_at,
+            } => {
+                self.busy_us += time_us;
+                self.servers[server].running -= 1;
+                self.start_iterations(server);
+                let overhead_us = self.now - received_at - time_us;
+                self.schedule(
+                    self.now + self.latency_us,
+                    Event::Update {
+                        server,
+                        perm_id,
+                        time_us,
+                        result: Some((overhead_us, sent_at)),
+                    },
+                );
+            }
+            Event::Update {
+                server,
+                perm_id,
+                time_us,
+                result,
+            } => {
+                let update = match result {
+                    None => ServerUpdate::InitDone {
+                        hash: String::new(),
+                    },
+                    Some((overhead_us, sent_at)) => {
+                        if let Some(&index) = self.client_by_perm.get(&perm_id) {
+                            self.clients[index].latency_us.push(self.now - sent_at);
+                        }
+                        ServerUpdate::Result {
+                            // Servers that report no overhead are treated as
+                            // legacy ones.
+                            overhead_us: overhead_us.max(1) as i64,
+                            compressed_source: None,
+                            has_source: false,
+                            more_props: HashMap::new(),
+                        }
+                    }
+                };
+                let sim_server = &mut self.servers[server];
+                let effect = server::handle_update(
+                    &self.m,
+                    &mut sim_server.state,
+                    self.scheduler,
+                    &sim_server.name,
+                    perm_id,
+                    time_us as f64,
+                    update,
+                )?;
+                let request_work = server::work_to_request(
+                    &mut sim_server.state,
+                    effect.more_work,
+                    sim_server.credits,
+                );
+                sim_server.credits =
+                    (sim_server.credits + request_work).min(SERVER_WORK_QUEUE_SIZE);
+                self.pump(server);
+            }
+        }
+        Ok(())
+    }
+
+    fn start_client(&mut self, index: usize) {
+        let spec = self.client_specs[index];
+        let client = &mut self.clients[index];
+        let mut data = PermuterData {
+            fn_name: format!("{}_{}", spec.user, index),
+            compressed_source: Vec::new(),
+            compressed_target_o_bin: Vec::new(),
+            source_hash: String::new(),
+            target_o_bin_hash: String::new(),
+            required_tags: BTreeSet::new(),
+            docker_image: None,
+            more_props: HashMap::new(),
+        };
+        data.hash_blobs();
+        let perm = Permuter::new(
+            Arc::new(data),
+            client.user_id.clone(),
+            spec.user.clone(),
+            spec.priority,
+        );
+        let (result_tx, result_rx) = mpsc::unbounded_channel();
+        let seeds = SeedGenerator::new(&SeedSpec {
+            count: u64::MAX,
+            repeat: true,
+            rng_seed: index as u64,
+        });
+        let m = self.m.get_mut().unwrap();
+        let perm_id = m.add_permuter(perm);
+        let attachment_id = m.attach_client(
+            perm_id,
+            client.user_id.clone(),
+            spec.user.clone(),
+            spec.priority,
+            result_tx,
+            Some(seeds),
+        );
+        self.client_by_perm.insert(perm_id, index);
+        client.running = Some((perm_id, attachment_id, result_rx));
+    }
+
+    /// Let a client receive its results, which frees up room for more work.
+    async fn drain_results(&mut self, index: usize) {
+        // Receiving uses up tokio's cooperative budget, after which receives
+        // return pending even when results are queued; yielding resets it.
+        let _ = tokio::task::yield_now().await;
+        let client = &mut self.clients[index];
+        if let Some((perm_id, attachment_id, ref mut result_rx)) = client.running {
+            while let Some(Some(res)) = result_rx.recv().now_or_never().await {
+                if let PermuterResult::Result(_, ServerUpdate::Result { .. }, time_us) = res {
+                    client.iterations += 1;
+                    client.cpu_us += time_us;
+                }
+                if let Some(perm) = self.m.get_mut().unwrap().permuters.get_mut(&perm_id) {
+                    if let Some(attached) = perm.client_mut(attachment_id) {
+                        attached.semaphore.release();
+                    }
+                }
+            }
+        }
+    }
+
+    fn start_iterations(&mut self, server: usize) {
+        let mut started = Vec::new();
+        let sim_server = &mut self.servers[server];
+        while sim_server.running < sim_server.cores {
+            let (perm_id, received_at, sent_at) = match sim_server.queue.pop_front() {
+                Some(item) => item,
+                None => break,
+            };
+            sim_server.running += 1;
+            let index = self.client_by_perm[&perm_id];
+            let time_us = (self.clients[index].iteration_us as f64 / sim_server.speed) as u64;
+            let event = Event::IterationDone {
+                server,
+                perm_id,
+                time_us,
+                received_at,
+                sent_at,
+            };
+            started.push((self.now + time_us, event));
+        }
+        for (time, event) in started {
+            self.schedule(time, event);
+        }
+    }
+
+    /// Run a server's work chooser for as long as it can make progress.
+    fn pump(&mut self, server: usize) {
+        loop {
+            let sim_server = &mut self.servers[server];
+            if sim_server.awaiting_credit {
+                if sim_server.credits == 0 {
+                    return;
+                }
+                sim_server.credits -= 1;
+                sim_server.awaiting_credit = false;
+            }
+            let message = match server::try_next_work_message(&self.m, &mut sim_server.state) {
+                Some(message) => message,
+                None => return,
+            };
+            sim_server.awaiting_credit = server::requires_response(&message);
+            let work = match message.to_send {
+                ToSend::Work(_) => true,
+                ToSend::Add { .. } => false,
+                ToSend::Remove => continue,
+            };
+            self.schedule(
+                self.now + self.latency_us,
+                Event::Deliver {
+                    server,
+                    perm_id: message.permuter,
+                    work,
+                    sent_at: self.now,
+                },
+            );
+        }
+    }
+
+    fn pump_all(&mut self) {
+        for server in 0..self.servers.len() {
+            self.pump(server);
+        }
+    }
+
+    fn report(&self, kind: SchedulerKind) -> Report {
+        let total_cpu: f64 = self.clients.iter().map(|client| client.cpu_us).sum();
+        let share = |cpu_us: f64| {
+            if total_cpu > 0.0 {
+                cpu_us / total_cpu
+            } else {
+                0.0
+            }
+        };
+        let mut all_latencies: Vec<u64> = self
+            .clients
+            .iter()
+            .flat_map(|client| client.latency_us.iter().copied())
+            .collect();
+        all_latencies.sort_unstable();
+        let mean_ms = |latencies: &[u64]| {
+            if latencies.is_empty() {
+                0.0
+            } else {
+                latencies.iter().sum::<u64>() as f64 / latencies.len() as f64 / 1000.0
+            }
+        };
+
+        let clients: Vec<ClientReport> = self
+            .clients
+            .iter()
+            .zip(&self.client_specs)
+            .map(|(client, spec)| ClientReport {
+                user: spec.user.clone(),
+                priority: spec.priority,
+                iterations: client.iterations,
+                cpu_share: share(client.cpu_us),
+                mean_latency_ms: mean_ms(&client.latency_us),
+            })
+            .collect();
+
+        // A user's priority is that of their highest-priority job, as in
+        // MutableState::user_priority.
+        let mut users: Vec<UserReport> = Vec::new();
+        for (client, spec) in self.clients.iter().zip(&self.client_specs) {
+            match users.iter_mut().find(|user| user.user == spec.user) {
+                Some(user) => {
+                    user.priority = user.priority.max(spec.priority);
+                    user.cpu_share += share(client.cpu_us);
+                }
+                None => users.push(UserReport {
+                    user: spec.user.clone(),
+                    priority: spec.priority,
+                    cpu_share: share(client.cpu_us),
+                    fair_share: 0.0,
+                }),
+            }
+        }
+        let total_priority: f64 = users.iter().map(|user| user.priority).sum();
+        for user in &mut users {
+            user.fair_share = user.priority / total_priority;
+        }
+        let normalized: Vec<f64> = users
+            .iter()
+            .map(|user| user.cpu_share / user.priority)
+            .collect();
+        let sum: f64 = normalized.iter().sum();
+        let sum_sq: f64 = normalized.iter().map(|x| x * x).sum();
+        let fairness = if sum_sq > 0.0 {
+            sum * sum / (normalized.len() as f64 * sum_sq)
+        } else {
+            0.0
+        };
+
+        let total_cores: usize = self.servers.iter().map(|server| server.cores).sum();
+        let available_us = total_cores as f64 * self.scenario.duration_secs * 1_000_000.0;
+        Report {
+            scheduler: kind,
+            utilization: if available_us > 0.0 {
+                self.busy_us as f64 / available_us
+            } else {
+                0.0
+            },
+            fairness,
+            mean_latency_ms: mean_ms(&all_latencies),
+            p95_latency_ms: all_latencies
+                .get(all_latencies.len() * 95 / 100)
+                .map_or(0.0, |&us| us as f64 / 1000.0),
+            iterations: self.clients.iter().map(|client| client.iterations).sum(),
+            events: self.event_count,
+            clients,
+            users,
+        }
+    }
+}
+
+/// Simulate a scenario with the given scheduling policy.
+async fn simulate(scenario: &Scenario, kind: SchedulerKind) -> SimpleResult<Report> {
+    let scheduler = kind.build();
+    let mut sim = Simulation::new(scenario, scheduler.as_ref());
+    sim.run().await?;
+    Ok(sim.report(kind))
+}
+
+fn print_report(report: &Report) {
+    println!("scheduler: {:?}", report.scheduler);
+    println!(
+        "utilization {:.3}, fairness {:.3}, latency mean {:.1} ms, p95 {:.1} ms, {} iterations",
+        report.utilization,
+        report.fairness,
+        report.mean_latency_ms,
+        report.p95_latency_ms,
+        report.iterations
+    );
+    println!(
+        "{:16} {:>8} {:>10} {:>9} {:>10}",
+        "user", "priority", "CPU share", "fair", "iterations"
+    );
+    for user in &report.users {
+        let iterations: u64 = report
+            .clients
+            .iter()
+            .filter(|client| client.user == user.user)
+            .map(|client| client.iterations)
+            .sum();
+        println!(
+            "{:16} {:>8.2} {:>10.3} {:>9.3} {:>10}",
+            user.user, user.priority, user.cpu_share, user.fair_share, iterations
+        );
+    }
+}
+
+pub(crate) async fn run_simulate(opts: SimulateOpts) -> SimpleResult<()> {
+    let scenario: Scenario = toml::from_str(&fs::read_to_string(&opts.scenario)?)?;
+    let kinds = if opts.scheduler.is_empty() {
+        vec![SchedulerKind::default()]
+    } else {
+        opts.scheduler.clone()
+    };
+    let mut reports = Vec::new();
+    for kind in kinds {
+        let start = Instant::now();
+        let report = simulate(&scenario, kind).await?;
+        // Wall time isn't deterministic, so keep it out of the report.
+        let secs = start.elapsed().as_secs_f64();
+        eprintln!(
+            "{:?}: {} events in {:.2} s ({:.0} events/s, {:.0} iterations/s)",
+            kind,
+            report.events,
+            secs,
+            report.events as f64 / secs,
+            report.iterations as f64 / secs
+        );
+        reports.push(report);
+    }
+
+    if opts.json {
+        println!("{}", serde_json::to_string(&reports)?);
+        return Ok(());
+    }
+    for (i, report) in reports.iter().enumerate() {
+        if i != 0 {
+            println!();
+        }
+        print_report(report);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One user with four jobs and one with a single job, sharing two
+    /// servers. Seeds are generated deterministically, so results are stable.
+    const SCENARIO: &str = r#"
+        duration_secs = 120.0
+        latency_ms = 5.0
+
+        [[servers]]
+        count = 2
+        cores = 4
+
+        [[clients]]
+        user = "alice"
+        count = 4
+        iteration_ms = 100.0
+
+        [[clients]]
+        user = "bob"
+        iteration_ms = 100.0
+    "#;
+
+    #[tokio::test]
+    async fn energy_is_fairer_than_even() {
+        let scenario: Scenario = toml::from_str(SCENARIO).unwrap();
+        let energy = simulate(&scenario, SchedulerKind::Energy).await.unwrap();
+        let even = simulate(&scenario, SchedulerKind::Even).await.unwrap();
+
+        // Energy splits server time between users, Even between jobs.
+        assert!(energy.fairness > 0.99, "fairness {}", energy.fairness);
+        assert!(even.fairness < 0.8, "fairness {}", even.fairness);
+        let bob = |report: &Report| {
+            report
+                .users
+                .iter()
+                .find(|u| u.user == "bob")
+                .unwrap()
+                .cpu_share
+        };
+        assert!((bob(&energy) - 0.5).abs() < 0.02);
+        assert!((bob(&even) - 0.2).abs() < 0.02);
+
+        // Fairness doesn't come at the expense of keeping servers busy.
+        assert!(
+            energy.utilization > 0.95,
+            "utilization {}",
+            energy.utilization
+        );
+        assert!(energy.utilization >= even.utilization - 0.01);
+    }
+
+    #[tokio::test]
+    async fn simulation_is_deterministic() {
+        let scenario: Scenario = toml::from_str(SCENARIO).unwrap();
+        let a = simulate(&scenario, SchedulerKind::Energy).await.unwrap();
+        let b = simulate(&scenario, SchedulerKind::Energy).await.unwrap();
+        assert_eq!(
+            serde_json::to_string(&a).unwrap(),
+            serde_json::to_string(&b).unwrap()
+        );
+    }
+}
@@ -1,11 +1,46 @@
 use std::future::Future;
+use std::time::{Duration, Instant};
 
+use chrono::Utc;
+use serde::Deserialize;
 use tokio::sync::mpsc;
 
-use crate::db::{Stats, UserId};
+use crate::db::{BucketStats, Discovery, Granularity, Scope, Stats, UserId, DB};
 use crate::save::SaveableDB;
 
 const CHANNEL_CAPACITY: usize = 10000;
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How many days to keep time-bucketed stats for, per bucket size. Daily
+/// stats are kept forever unless a limit is given.
+#[derive(Clone, Deserialize)]
+pub struct Retention {
+    #[serde(default = "default_hourly_days")]
+    hourly_days: u32,
+    daily_days: Option<u32>,
+}
+
+fn default_hourly_days() -> u32 {
+    14
+}
+
+impl Default for Retention {
+    fn default() -> Retention {
+        Retention {
+            hourly_days: default_hourly_days(),
+            daily_days: None,
+        }
+    }
+}
+
+impl Retention {
+    fn days(&self, granularity: Granularity) -> Option<u32> {
+        match granularity {
+            Granularity::Hour => Some(self.hourly_days),
+            Granularity::Day => self.daily_days,
+        }
+    }
+}
 
 #[derive(Clone, Copy)]
 pub enum Outcome {
@@ -28,6 +63,13 @@ pub enum Record {
     ServerNewFunction {
         server: UserId,
     },
+    CpuTime {
+        server: UserId,
+        client: UserId,
+        fn_name: String,
+        time_us: f64,
+    },
+    Discovery(Discovery),
 }
 
 fn add_stats(stats: &mut Stats, outcome: Outcome) {
@@ -40,10 +82,40 @@ fn add_stats(stats: &mut Stats, outcome: Outcome) {
     stats.iterations += 1;
 }
 
-async fn stats_writer(db: &SaveableDB, mut rx: mpsc::Receiver<Record>) {
+/// Update the current time buckets of the given scopes, at every granularity.
+fn add_rollups(db: &mut DB, scopes: &[Scope], f: impl Fn(&mut BucketStats)) {
+    let now = Utc::now().timestamp();
+    for &granularity in &Granularity::ALL {
+        for scope in scopes {
+            f(db.rollup(granularity, now, scope.clone()));
+        }
+    }
+}
+
+fn prune_rollups(db: &mut DB, retention: &Retention) {
+    let now = Utc::now().timestamp();
+    for &granularity in &Granularity::ALL {
+        if let Some(days) = retention.days(granularity) {
+            let cutoff = granularity.bucket(now) - i64::from(days) * 24 * 60 * 60;
+            db.prune_rollups(granularity, cutoff);
+        }
+    }
+}
+
+async fn stats_writer(
+    db: &SaveableDB,
+    credit_half_life: Option<f64>,
+    retention: Retention,
+    mut rx: mpsc::Receiver<Record>,
+) {
+    let mut last_prune: Option<Instant> = None;
     loop {
         let record = rx.recv().await.unwrap();
         db.write(false, |db| {
+            if last_prune.is_none_or(|t| t.elapsed() >= PRUNE_INTERVAL) {
+                prune_rollups(db, &retention);
+                last_prune = Some(Instant::now());
+            }
             match record {
                 Record::WorkDone {
                     server,
@@ -52,25 +124,70 @@ async fn stats_writer(db: &SaveableDB, mut rx: mpsc::Receiver<Record>) {
                     outcome,
                 } => {
                     add_stats(&mut db.total_stats, outcome);
-                    add_stats(db.func_stat(fn_name), outcome);
-                    if let Some(user) = db.users.get_mut(&client) {
+                    add_stats(db.func_stat(fn_name.clone()), outcome);
+                    if let Some(user) = db.user_mut(&client) {
                         add_stats(&mut user.client_stats, outcome);
                     }
-                    if let Some(user) = db.users.get_mut(&server) {
+                    if let Some(user) = db.user_mut(&server) {
                         add_stats(&mut user.server_stats, outcome);
                     }
+                    let scopes = [
+                        Scope::Total,
+                        Scope::Function(fn_name),
+                        Scope::Client(client),
+                        Scope::Server(server),
+                    ];
+                    add_rollups(db, &scopes, |bucket| add_stats(&mut bucket.stats, outcome));
                 }
                 Record::ClientNewFunction { client, fn_name } => {
-                    db.func_stat(fn_name).functions += 1;
-                    if let Some(user) = db.users.get_mut(&client) {
+                    db.func_stat(fn_name.clone()).functions += 1;
+                    if let Some(user) = db.user_mut(&client) {
                         user.client_stats.functions += 1;
                     }
                     db.total_stats.functions += 1;
+                    let scopes = [
+                        Scope::Total,
+                        Scope::Function(fn_name),
+                        Scope::Client(client),
+                    ];
+                    add_rollups(db, &scopes, |bucket| bucket.stats.functions += 1);
                 }
                 Record::ServerNewFunction { server } => {
-                    if let Some(user) = db.users.get_mut(&server) {
+                    if let Some(user) = db.user_mut(&server) {
                         user.server_stats.functions += 1;
                     }
+                    add_rollups(db, &[Scope::Server(server)], |bucket| {
+                        bucket.stats.functions += 1
+                    });
+                }
+                Record::CpuTime {
+                    server,
+                    client,
+                    fn_name,
+                    time_us,
+                } => {
+                    if let Some(user) = db.user_mut(&client) {
+                        user.cpu_usage.add(time_us);
+                    }
+                    // Running one's own jobs doesn't earn credit.
+                    if let Some(half_life) = credit_half_life {
+                        if server != client {
+                            if let Some(user) = db.user_mut(&server) {
+                                let hours = time_us / (60.0 * 60.0 * 1_000_000.0);
+                                user.credit.add(hours, half_life);
+                            }
+                        }
+                    }
+                    let scopes = [
+                        Scope::Total,
+                        Scope::Function(fn_name),
+                        Scope::Client(client),
+                        Scope::Server(server),
+                    ];
+                    add_rollups(db, &scopes, |bucket| bucket.cpu_us += time_us);
+                }
+                Record::Discovery(discovery) => {
+                    db.add_discovery(discovery);
                 }
             };
         })
@@ -78,11 +195,15 @@ async fn stats_writer(db: &SaveableDB, mut rx: mpsc::Receiver<Record>) {
     }
 }
 
-pub fn stats_thread(db: &SaveableDB) -> (impl Future<Output = ()>, mpsc::Sender<Record>) {
+pub fn stats_thread(
+    db: &SaveableDB,
+    credit_half_life: Option<f64>,
+    retention: Retention,
+) -> (impl Future<Output = ()>, mpsc::Sender<Record>) {
     let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
     let db = db.clone();
     let fut = async move {
-        stats_writer(&db, rx).await;
+        stats_writer(&db, credit_half_life, retention, rx).await;
     };
     (fut, tx)
 }
@@ -0,0 +1,86 @@
+use chrono::{TimeZone, Utc};
+use serde::Serialize;
+
+use crate::db::{BucketStats, Granularity, RollupKey, Scope};
+use crate::save;
+use crate::util::SimpleResult;
+use crate::StatsOpts;
+
+#[derive(Serialize)]
+struct BucketView {
+    start: i64,
+    iterations: u64,
+    improvements: u64,
+    matches: u64,
+    functions: u64,
+    cpu_hours: f64,
+}
+
+impl BucketView {
+    fn new(start: i64, bucket: &BucketStats) -> BucketView {
+        BucketView {
+            start,
+            iterations: bucket.stats.iterations,
+            improvements: bucket.stats.improvements,
+            matches: bucket.stats.matches,
+            functions: bucket.stats.functions,
+            cpu_hours: bucket.cpu_us / (60.0 * 60.0 * 1_000_000.0),
+        }
+    }
+}
+
+pub(crate) fn run_stats(opts: StatsOpts) -> SimpleResult<()> {
+    let (db, _) = save::load_file(&opts.db)?;
+
+    let scope = match (&opts.client, &opts.server, &opts.function) {
+        (None, None, None) => Scope::Total,
+        (Some(user), None, None) => Scope::Client(db.find_user(user)?),
+        (None, Some(user), None) => Scope::Server(db.find_user(user)?),
+        (None, None, Some(fn_name)) => Scope::Function(fn_name.clone()),
+        _ => Err("At most one of --client, --server and --function may be given")?,
+    };
+
+    let granularity = opts.granularity;
+    let now = granularity.bucket(Utc::now().timestamp());
+    let empty = BucketStats::default();
+    let buckets: Vec<BucketView> = (0..i64::from(opts.last))
+        .rev()
+        .map(|i| {
+            let key = RollupKey {
+                granularity,
+                start: now - i * granularity.secs(),
+                scope: scope.clone(),
+            };
+            BucketView::new(key.start, db.rollups().get(&key).unwrap_or(&empty))
+        })
+        .collect();
+
+    if opts.json {
+        println!("{}", serde_json::to_string(&buckets)?);
+        return Ok(());
+    }
+
+    let time_format = match granularity {
+        Granularity::Hour => "%Y-%m-%d %H:00",
+        Granularity::Day => "%Y-%m-%d",
+    };
+    println!(
+        "{:16} {:>12} {:>12} {:>8} {:>9} {:>9}",
+        "start (UTC)", "iterations", "improvements", "matches", "functions", "CPU hours"
+    );
+    for bucket in buckets {
+        println!(
+            "{:16} {:>12} {:>12} {:>8} {:>9} {:>9.2}",
+            Utc.timestamp_opt(bucket.start, 0)
+                .unwrap()
+                .format(time_format)
+                .to_string(),
+            bucket.iterations,
+            bucket.improvements,
+            bucket.matches,
+            bucket.functions,
+            bucket.cpu_hours
+        );
+    }
+    Ok(())
+}
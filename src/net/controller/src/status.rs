@@ -0,0 +1,158 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::db::Stats;
+use crate::http::Response;
+use crate::{MutableState, PermuterId, State};
+
+#[derive(Serialize)]
+struct StatsView {
+    iterations: u64,
+    improvements: u64,
+    matches: u64,
+    functions: u64,
+}
+
+impl From<&Stats> for StatsView {
+    fn from(stats: &Stats) -> StatsView {
+        StatsView {
+            iterations: stats.iterations,
+            improvements: stats.improvements,
+            matches: stats.matches,
+            functions: stats.functions,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ServerStatus {
+    owner: String,
+    min_priority: f64,
+    num_cores: f64,
+    tags: Vec<String>,
+    docker_images: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct PermuterStatus {
+    id: PermuterId,
+    fn_name: String,
+    owner: String,
+    required_tags: Vec<String>,
+    docker_image: String,
+    priority: f64,
+    /// number of clients sharing the job
+    clients: usize,
+    loaded_servers: usize,
+    /// results per second
+    throughput: f64,
+    /// estimated time per iteration, in microseconds
+    time_us: Option<f64>,
+    /// the same, for each server that has reported results
+    server_time_us: Vec<ServerTimeStatus>,
+}
+
+#[derive(Serialize)]
+struct ServerTimeStatus {
+    owner: String,
+    time_us: f64,
+}
+
+#[derive(Serialize)]
+struct UserStatus {
+    name: String,
+    client_stats: StatsView,
+    server_stats: StatsView,
+}
+
+#[derive(Serialize)]
+struct Status {
+    servers: Vec<ServerStatus>,
+    permuters: Vec<PermuterStatus>,
+    total_stats: StatsView,
+    func_stats: BTreeMap<String, StatsView>,
+    users: Vec<UserStatus>,
+}
+
+fn snapshot(state: &State) -> Status {
+    let (servers, permuters) = {
+        let mut m = state.m.lock().unwrap();
+        let MutableState {
+            ref servers,
+            ref mut permuters,
+            ..
+        } = *m;
+        let server_statuses = servers
+            .values()
+            .map(|server| ServerStatus {
+                owner: server.owner_name.clone(),
+                min_priority: server.min_priority,
+                num_cores: server.num_cores,
+                tags: server.tags.iter().cloned().collect(),
+                docker_images: server.docker_images.iter().cloned().collect(),
+            })
+            .collect();
+        let mut permuter_statuses: Vec<PermuterStatus> = permuters
+            .iter_mut()
+            .map(|(&id, perm)| PermuterStatus {
+                id,
+                fn_name: perm.data.fn_name.clone(),
+                owner: perm.client_name.clone(),
+                required_tags: perm.data.required_tags.iter().cloned().collect(),
+                docker_image: perm.data.docker_image().to_string(),
+                priority: perm.priority,
+                clients: perm.clients.len(),
+                loaded_servers: perm.loaded_servers,
+                throughput: perm.results.rate(),
+                time_us: perm.time_us.value(),
+                server_time_us: perm
+                    .server_time_us
+                    .iter()
+                    .filter_map(|(&server_id, time_us)| {
+                        Some(ServerTimeStatus {
+                            owner: servers.get(server_id)?.owner_name.clone(),
+                            time_us: time_us.value()?,
+                        })
+                    })
+                    .collect(),
+            })
+            .collect();
+        permuter_statuses.sort_by_key(|perm| perm.id);
+        (server_statuses, permuter_statuses)
+    };
+
+    state.db.read(|db| {
+        let mut users: Vec<UserStatus> = db
+            .users()
+            .values()
+            .map(|user| UserStatus {
+                name: user.name.clone(),
+                client_stats: (&user.client_stats).into(),
+                server_stats: (&user.server_stats).into(),
+            })
+            .collect();
+        users.sort_by(|a, b| a.name.cmp(&b.name));
+        Status {
+            servers,
+            permuters,
+            total_stats: (&db.total_stats).into(),
+            func_stats: db
+                .func_stats()
+                .iter()
+                .map(|(fn_name, stats)| (fn_name.clone(), stats.into()))
+                .collect(),
+            users,
+        }
+    })
+}
+
+pub fn handle_request(state: &State, path: &str) -> Response {
+    match path {
+        "/status" => Response::ok(
+            "application/json",
+            serde_json::to_string(&snapshot(state)).unwrap(),
+        ),
+        _ => Response::not_found(),
+    }
+}
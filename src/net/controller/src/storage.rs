@@ -0,0 +1,471 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use serde_json::{json, Map, Value};
+use tempfile::NamedTempFile;
+
+use crate::db::{BucketStats, Dirty, RollupKey, Stats, DB};
+use crate::util::SimpleResult;
+
+/// A place to persist the database to.
+pub trait Storage: Send + 'static {
+    /// Data captured from the database for a save.
+    type Snapshot: Send;
+
+    /// How long to wait before saving after a change.
+    const SAVE_INTERVAL: Duration;
+
+    /// Load the database, in its JSON form, to be migrated and deserialized.
+    fn load(&mut self) -> SimpleResult<Value>;
+
+    /// Capture what needs to be saved. This is called with the database
+    /// locked, so should be quick.
+    fn snapshot(&self, db: &DB, dirty: Dirty) -> Self::Snapshot;
+
+    /// Write a snapshot to storage.
+    fn write(&mut self, snapshot: Self::Snapshot) -> SimpleResult<()>;
+
+    /// Copy the stored database to a new file next to it, with the given
+    /// suffix added to its name. Returns the path of the copy.
+    fn backup(&self, suffix: &str) -> SimpleResult<PathBuf>;
+}
+
+/// A path for a backup that doesn't already exist, made by adding a suffix
+/// and if necessary a number.
+fn backup_path(path: &Path, suffix: &str) -> PathBuf {
+    let with_suffix = |n: u32| {
+        let mut backup = path.as_os_str().to_owned();
+        backup.push(suffix);
+        if n != 0 {
+            backup.push(format!(".{}", n));
+        }
+        PathBuf::from(backup)
+    };
+    (0..)
+        .map(with_suffix)
+        .find(|backup| !backup.exists())
+        .unwrap()
+}
+
+pub fn is_sqlite(path: &str) -> bool {
+    matches!(
+        Path::new(path).extension().and_then(|e| e.to_str()),
+        Some("sqlite" | "sqlite3")
+    )
+}
+
+/// The whole database as a single JSON file, which is rewritten on every save.
+pub struct JsonStorage {
+    path: PathBuf,
+}
+
+impl JsonStorage {
+    pub fn open(path: &str) -> JsonStorage {
+        JsonStorage {
+            path: PathBuf::from(path),
+        }
+    }
+
+    pub fn create(path: &str, db: &DB) -> SimpleResult<()> {
+        let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        serde_json::to_writer(&file, db)?;
+        file.sync_all()?;
+        Ok(())
+    }
+}
+
+impl Storage for JsonStorage {
+    type Snapshot = String;
+
+    const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+    fn load(&mut self) -> SimpleResult<Value> {
+        let file = std::fs::File::open(&self.path)?;
+        Ok(serde_json::from_reader(&file)?)
+    }
+
+    fn snapshot(&self, db: &DB, _dirty: Dirty) -> String {
+        serde_json::to_string(db).unwrap()
+    }
+
+    fn write(&mut self, data: String) -> SimpleResult<()> {
+        // Atomically save the file by creating and renaming a temp file in
+        // the same directory.
+        let parent_dir = self.path.parent().unwrap_or_else(|| Path::new("."));
+        let mut tempf = NamedTempFile::new_in(parent_dir)?;
+        tempf.write_all(data.as_bytes())?;
+        tempf.as_file().sync_all()?;
+        tempf.persist(&self.path)?;
+        Ok(())
+    }
+
+    fn backup(&self, suffix: &str) -> SimpleResult<PathBuf> {
+        let backup = backup_path(&self.path, suffix);
+        std::fs::copy(&self.path, &backup)?;
+        Ok(backup)
+    }
+}
+
+const SQLITE_SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS meta (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS users (
+        id TEXT PRIMARY KEY,
+        data TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS func_stats (
+        fn_name TEXT PRIMARY KEY,
+        iterations INTEGER NOT NULL,
+        improvements INTEGER NOT NULL,
+        matches INTEGER NOT NULL,
+        functions INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS total_stats (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        iterations INTEGER NOT NULL,
+        improvements INTEGER NOT NULL,
+        matches INTEGER NOT NULL,
+        functions INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS rollups (
+        granularity TEXT NOT NULL,
+        start INTEGER NOT NULL,
+        scope TEXT NOT NULL,
+        iterations INTEGER NOT NULL,
+        improvements INTEGER NOT NULL,
+        matches INTEGER NOT NULL,
+        functions INTEGER NOT NULL,
+        cpu_us REAL NOT NULL,
+        PRIMARY KEY (granularity, start, scope)
+    );
+    CREATE TABLE IF NOT EXISTS discoveries (
+        id INTEGER PRIMARY KEY,
+        data TEXT NOT NULL
+    );
+";
+
+/// Changed rows to be written to SQLite. Users are stored as JSON, and are
+/// None if they have been removed.
+pub struct SqliteChanges {
+    schema_version: u64,
+    users: Vec<(String, Option<String>)>,
+    func_stats: Vec<(String, Stats)>,
+    total_stats: Stats,
+    rollups: Vec<(RollupKey, Option<BucketStats>)>,
+    /// new discoveries, as JSON
+    discoveries: Vec<String>,
+    /// whether the discoveries replace the whole log
+    replace_discoveries: bool,
+}
+
+/// The database in SQLite, where each save only updates the rows that have
+/// changed.
+pub struct SqliteStorage {
+    path: PathBuf,
+    conn: Connection,
+}
+
+impl SqliteStorage {
+    fn connect(path: &str, flags: OpenFlags) -> SimpleResult<Connection> {
+        let conn = Connection::open_with_flags(path, flags)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        Ok(conn)
+    }
+
+    pub fn open(path: &str) -> SimpleResult<SqliteStorage> {
+        let conn = SqliteStorage::connect(path, OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+        // Create any tables added since the database was created.
+        conn.execute_batch(SQLITE_SCHEMA)?;
+        Ok(SqliteStorage {
+            path: PathBuf::from(path),
+            conn,
+        })
+    }
+
+    /// Open a database without changing anything about it, not even adding
+    /// missing tables, for loading only.
+    pub fn open_read_only(path: &str) -> SimpleResult<SqliteStorage> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        Ok(SqliteStorage {
+            path: PathBuf::from(path),
+            conn,
+        })
+    }
+
+    /// Whether a table exists in the database.
+    fn has_table(&self, name: &str) -> SimpleResult<bool> {
+        let count: i64 = self.conn.query_row(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?1",
+            params![name],
+            |row| row.get(0),
+        )?;
+        Ok(count != 0)
+    }
+
+    pub fn create(path: &str, db: &DB) -> SimpleResult<()> {
+        if Path::new(path).exists() {
+            Err(format!("{} already exists", path))?;
+        }
+        let conn = SqliteStorage::connect(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+        )?;
+        conn.execute_batch(SQLITE_SCHEMA)?;
+        let mut storage = SqliteStorage {
+            path: PathBuf::from(path),
+            conn,
+        };
+        let snapshot = storage.snapshot(db, db.all_dirty());
+        storage.write(snapshot)
+    }
+}
+
+fn stats_to_json(row: &rusqlite::Row<'_>, first: usize) -> rusqlite::Result<Value> {
+    let mut stats = Vec::new();
+    for i in first..first + 4 {
+        stats.push(row.get::<_, i64>(i)?);
+    }
+    Ok(json!(stats))
+}
+
+fn stats_params(stats: &Stats) -> [i64; 4] {
+    [
+        stats.iterations as i64,
+        stats.improvements as i64,
+        stats.matches as i64,
+        stats.functions as i64,
+    ]
+}
+
+impl Storage for SqliteStorage {
+    type Snapshot = SqliteChanges;
+
+    const SAVE_INTERVAL: Duration = Duration::from_secs(2);
+
+    fn load(&mut self) -> SimpleResult<Value> {
+        let mut users = Map::new();
+        let mut stmt = self.conn.prepare("SELECT id, data FROM users")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let data: String = row.get(1)?;
+            users.insert(row.get(0)?, serde_json::from_str(&data)?);
+        }
+
+        let mut func_stats = Map::new();
+        let mut stmt = self.conn.prepare(
+            "SELECT fn_name, iterations, improvements, matches, functions FROM func_stats",
+        )?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            func_stats.insert(row.get(0)?, stats_to_json(row, 1)?);
+        }
+
+        let total_stats = self
+            .conn
+            .query_row(
+                "SELECT iterations, improvements, matches, functions FROM total_stats",
+                [],
+                |row| stats_to_json(row, 0),
+            )
+            .or_else(|e| match e {
+                rusqlite::Error::QueryReturnedNoRows => Ok(json!([0, 0, 0, 0])),
+                e => Err(e),
+            })?;
+
+        // Tables added since the database was created are missing if it was
+        // opened read-only.
+        let mut rollups = Vec::new();
+        if self.has_table("rollups")? {
+            let mut stmt = self.conn.prepare(
+                "SELECT granularity, start, scope, iterations, improvements, matches, functions, cpu_us
+                    FROM rollups",
+            )?;
+            let mut rows = stmt.query([])?;
+            while let Some(row) = rows.next()? {
+                let granularity: String = row.get(0)?;
+                let start: i64 = row.get(1)?;
+                let scope: String = row.get(2)?;
+                let cpu_us: f64 = row.get(7)?;
+                rollups.push(json!([
+                    granularity,
+                    start,
+                    scope,
+                    stats_to_json(row, 3)?,
+                    cpu_us
+                ]));
+            }
+        }
+
+        let mut discoveries = Vec::new();
+        if self.has_table("discoveries")? {
+            let mut stmt = self
+                .conn
+                .prepare("SELECT data FROM discoveries ORDER BY id")?;
+            let mut rows = stmt.query([])?;
+            while let Some(row) = rows.next()? {
+                let data: String = row.get(0)?;
+                discoveries.push(serde_json::from_str::<Value>(&data)?);
+            }
+        }
+
+        let mut db = json!({
+            "users": users,
+            "func_stats": func_stats,
+            "total_stats": total_stats,
+            "rollups": rollups,
+            "discoveries": discoveries,
+        });
+
+        let schema_version: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT value FROM meta WHERE key = 'schema_version'",
+                [],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if let Some(version) = schema_version {
+            db["schema_version"] = serde_json::from_str(&version)?;
+        }
+        Ok(db)
+    }
+
+    fn snapshot(&self, db: &DB, dirty: Dirty) -> SqliteChanges {
+        SqliteChanges {
+            schema_version: db.schema_version(),
+            users: dirty
+                .users
+                .into_iter()
+                .map(|id| {
+                    let data = db
+                        .users()
+                        .get(&id)
+                        .map(|user| serde_json::to_string(user).unwrap());
+                    (id.to_hex(), data)
+                })
+                .collect(),
+            func_stats: dirty
+                .func_stats
+                .into_iter()
+                .filter_map(|fn_name| {
+                    let stats = db.func_stats().get(&fn_name)?.clone();
+                    Some((fn_name, stats))
+                })
+                .collect(),
+            total_stats: db.total_stats.clone(),
+            rollups: dirty
+                .rollups
+                .into_iter()
+                .map(|key| {
+                    let bucket = db.rollups().get(&key).cloned();
+                    (key, bucket)
+                })
+                .collect(),
+            discoveries: db.discoveries()[db.discoveries().len() - dirty.new_discoveries..]
+                .iter()
+                .map(|discovery| serde_json::to_string(discovery).unwrap())
+                .collect(),
+            replace_discoveries: dirty.all,
+        }
+    }
+
+    fn write(&mut self, changes: SqliteChanges) -> SimpleResult<()> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut upsert_user =
+                tx.prepare_cached("INSERT OR REPLACE INTO users (id, data) VALUES (?1, ?2)")?;
+            let mut delete_user = tx.prepare_cached("DELETE FROM users WHERE id = ?1")?;
+            for (id, data) in &changes.users {
+                match data {
+                    Some(data) => upsert_user.execute(params![id, data])?,
+                    None => delete_user.execute(params![id])?,
+                };
+            }
+
+            let mut upsert_func = tx.prepare_cached(
+                "INSERT OR REPLACE INTO func_stats
+                    (fn_name, iterations, improvements, matches, functions)
+                    VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for (fn_name, stats) in &changes.func_stats {
+                let [a, b, c, d] = stats_params(stats);
+                upsert_func.execute(params![fn_name, a, b, c, d])?;
+            }
+
+            let [a, b, c, d] = stats_params(&changes.total_stats);
+            tx.execute(
+                "INSERT OR REPLACE INTO total_stats
+                    (id, iterations, improvements, matches, functions)
+                    VALUES (0, ?1, ?2, ?3, ?4)",
+                params![a, b, c, d],
+            )?;
+
+            let mut upsert_rollup = tx.prepare_cached(
+                "INSERT OR REPLACE INTO rollups
+                    (granularity, start, scope, iterations, improvements, matches, functions, cpu_us)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            )?;
+            let mut delete_rollup = tx.prepare_cached(
+                "DELETE FROM rollups WHERE granularity = ?1 AND start = ?2 AND scope = ?3",
+            )?;
+            for (key, bucket) in &changes.rollups {
+                let granularity = key.granularity.name();
+                let scope = key.scope.to_string();
+                match bucket {
+                    Some(bucket) => {
+                        let [a, b, c, d] = stats_params(&bucket.stats);
+                        upsert_rollup.execute(params![
+                            granularity,
+                            key.start,
+                            scope,
+                            a,
+                            b,
+                            c,
+                            d,
+                            bucket.cpu_us
+                        ])?
+                    }
+                    None => delete_rollup.execute(params![granularity, key.start, scope])?,
+                };
+            }
+
+            if changes.replace_discoveries {
+                tx.execute("DELETE FROM discoveries", [])?;
+            }
+            let mut insert_discovery =
+                tx.prepare_cached("INSERT INTO discoveries (data) VALUES (?1)")?;
+            for data in &changes.discoveries {
+                insert_discovery.execute(params![data])?;
+            }
+
+            tx.execute(
+                "INSERT OR REPLACE INTO meta (key, value) VALUES ('schema_version', ?1)",
+                params![changes.schema_version.to_string()],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn backup(&self, suffix: &str) -> SimpleResult<PathBuf> {
+        let backup = backup_path(&self.path, suffix);
+        let backup_str = backup.to_str().ok_or("backup path is not valid UTF-8")?;
+        self.conn.execute("VACUUM INTO ?1", params![backup_str])?;
+        Ok(backup)
+    }
+}
+
+/// Create a new database file, using the storage backend implied by its
+/// file extension.
+pub fn create(path: &str, db: &DB) -> SimpleResult<()> {
+    if is_sqlite(path) {
+        SqliteStorage::create(path, db)
+    } else {
+        JsonStorage::create(path, db)
+    }
+}
@@ -0,0 +1,183 @@
+use crate::db::{Limits, Role, Roles, Stats, UserId, DB};
+use crate::save::{self, SaveableDB};
+use crate::util::SimpleResult;
+use crate::vouch::validate_name;
+use crate::{UsersOpts, UsersSubCommand};
+
+fn trust_chain(db: &DB, id: &UserId) -> String {
+    let chain: Vec<String> = db
+        .trust_chain(id)
+        .into_iter()
+        .map(|voucher| match db.users().get(voucher) {
+            Some(user) => user.name.clone(),
+            None => format!("(revoked {})", voucher.to_hex()),
+        })
+        .collect();
+    if chain.is_empty() {
+        "-".to_string()
+    } else {
+        chain.join(" <- ")
+    }
+}
+
+fn format_roles(roles: &Roles) -> String {
+    let roles: Vec<String> = roles.iter().map(Role::to_string).collect();
+    if roles.is_empty() {
+        "-".to_string()
+    } else {
+        roles.join(", ")
+    }
+}
+
+fn parse_roles(roles: &str) -> SimpleResult<Roles> {
+    Ok(roles
+        .split(',')
+        .map(str::trim)
+        .filter(|role| !role.is_empty())
+        .map(str::parse)
+        .collect::<Result<_, _>>()?)
+}
+
+fn format_limits(limits: &Limits) -> String {
+    fn show<T: ToString>(limit: Option<T>) -> String {
+        limit.map_or_else(|| "default".to_string(), |l| l.to_string())
+    }
+    format!(
+        "max permuters {}, max priority {}, daily CPU hours {}",
+        show(limits.max_permuters),
+        show(limits.max_priority),
+        show(limits.daily_cpu_hours)
+    )
+}
+
+fn format_stats(stats: &Stats) -> String {
+    format!(
+        "{} iterations, {} improvements, {} matches, {} functions",
+        stats.iterations, stats.improvements, stats.matches, stats.functions
+    )
+}
+
+fn list_users(db: &DB) {
+    let mut users: Vec<_> = db.users().iter().collect();
+    users.sort_by(|a, b| a.1.name.cmp(&b.1.name));
+    for (id, user) in users {
+        println!(
+            "{} {}\n    trusted by: {}\n    roles: {}\n    client: {}\n    server: {}",
+            id.to_hex(),
+            user.name,
+            trust_chain(db, id),
+            format_roles(&user.roles),
+            format_stats(&user.client_stats),
+            format_stats(&user.server_stats),
+        );
+    }
+}
+
+fn show_user(db: &DB, id: &UserId) {
+    let user = &db.users()[id];
+    let mut vouched: Vec<&str> = db
+        .users()
+        .values()
+        .filter(|u| u.trusted_by.as_ref() == Some(id))
+        .map(|u| u.name.as_str())
+        .collect();
+    vouched.sort_unstable();
+    println!("name: {}", user.name);
+    println!("id: {}", id.to_hex());
+    println!("trusted by: {}", trust_chain(db, id));
+    println!("vouched for: {}", vouched.join(", "));
+    println!("roles: {}", format_roles(&user.roles));
+    println!("limits: {}", format_limits(&user.limits));
+    println!("CPU hours today: {:.2}", user.cpu_usage.today_hours());
+    println!("client stats: {}", format_stats(&user.client_stats));
+    println!("server stats: {}", format_stats(&user.server_stats));
+}
+
+pub(crate) async fn run_users(opts: UsersOpts) -> SimpleResult<()> {
+    // Only changes to users may migrate or otherwise write the database.
+    match opts.sub {
+        UsersSubCommand::List(_) => {
+            let (db, _) = save::load_file(&opts.db)?;
+            list_users(&db);
+        }
+        UsersSubCommand::Show(show_opts) => {
+            let (db, _) = save::load_file(&opts.db)?;
+            show_user(&db, &db.find_user(&show_opts.user)?);
+        }
+        sub => modify_users(&opts.db, sub).await?,
+    }
+    Ok(())
+}
+
+async fn modify_users(path: &str, sub: UsersSubCommand) -> SimpleResult<()> {
+    let (save_fut, db) = SaveableDB::open(path)?;
+    tokio::spawn(async move {
+        if let Err(e) = save_fut.await {
+            eprintln!("Failed to save! {:?}", e);
+            std::process::exit(1);
+        }
+    });
+
+    match sub {
+        UsersSubCommand::List(_) | UsersSubCommand::Show(_) => unreachable!(),
+        UsersSubCommand::Revoke(opts) => {
+            let id = db.read(|db| db.find_user(&opts.user))?;
+            let removed = db
+                .write(true, |db| {
+                    let others = db.vouchees(&id).len();
+                    if !opts.cascade && others != 0 {
+                        println!(
+                            "Note: {} user(s) vouched for by {} remain; use --cascade to revoke them too.",
+                            others, db.users()[&id].name
+                        );
+                    }
+                    db.revoke(&id, opts.cascade)
+                })
+                .await;
+            for (_, user) in removed {
+                println!("Revoked {}.", user.name);
+            }
+        }
+        UsersSubCommand::Rename(opts) => {
+            validate_name(&opts.name)?;
+            let id = db.read(|db| db.find_user(&opts.user))?;
+            let old_name = db
+                .write(true, |db| {
+                    let user = db.user_mut(&id).unwrap();
+                    std::mem::replace(&mut user.name, opts.name.clone())
+                })
+                .await;
+            println!("Renamed {} to {}.", old_name, opts.name);
+        }
+        UsersSubCommand::SetRoles(opts) => {
+            let roles = parse_roles(&opts.roles)?;
+            let id = db.read(|db| db.find_user(&opts.user))?;
+            let name = db
+                .write(true, |db| {
+                    let user = db.user_mut(&id).unwrap();
+                    user.roles = roles.clone();
+                    user.name.clone()
+                })
+                .await;
+            println!("Set roles of {} to {}.", name, format_roles(&roles));
+        }
+        UsersSubCommand::SetLimits(opts) => {
+            let id = db.read(|db| db.find_user(&opts.user))?;
+            let (name, limits) = db
+                .write(true, |db| {
+                    let user = db.user_mut(&id).unwrap();
+                    if opts.reset {
+                        user.limits = Limits::default();
+                    }
+                    let limits = &mut user.limits;
+                    limits.max_permuters = opts.max_permuters.or(limits.max_permuters);
+                    limits.max_priority = opts.max_priority.or(limits.max_priority);
+                    limits.daily_cpu_hours = opts.daily_cpu_hours.or(limits.daily_cpu_hours);
+                    (user.name.clone(), user.limits.clone())
+                })
+                .await;
+            println!("Set limits of {} to {}.", name, format_limits(&limits));
+        }
+    }
+    Ok(())
+}
@@ -2,11 +2,91 @@ use std::error::Error;
 use std::future::Future;
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
 use pin_project::pin_project;
+use sodiumoxide::randombytes::randombytes;
 
 pub type SimpleResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
 
+/// A random token for resuming a session.
+pub fn resume_token() -> String {
+    hex::encode(randombytes(16))
+}
+
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// How much each new sample moves a MovingAverage.
+const MOVING_AVERAGE_WEIGHT: f64 = 0.1;
+
+/// Exponential moving average of a series of samples, starting out at the
+/// first one.
+#[derive(Clone, Copy, Default)]
+pub struct MovingAverage {
+    value: Option<f64>,
+}
+
+impl MovingAverage {
+    pub fn add(&mut self, sample: f64) {
+        self.value = Some(match self.value {
+            Some(value) => value + MOVING_AVERAGE_WEIGHT * (sample - value),
+            None => sample,
+        });
+    }
+
+    pub fn value(&self) -> Option<f64> {
+        self.value
+    }
+}
+
+/// Measures the rate of events, over windows of a few seconds.
+pub struct RateCounter {
+    window_start: Instant,
+    window_count: u64,
+    last_rate: Option<f64>,
+}
+
+impl RateCounter {
+    pub fn new() -> RateCounter {
+        RateCounter {
+            window_start: Instant::now(),
+            window_count: 0,
+            last_rate: None,
+        }
+    }
+
+    fn roll(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed >= RATE_WINDOW {
+            self.last_rate = Some(self.window_count as f64 / elapsed.as_secs_f64());
+            self.window_start = now;
+            self.window_count = 0;
+        }
+    }
+
+    pub fn record(&mut self) {
+        self.roll(Instant::now());
+        self.window_count += 1;
+    }
+
+    /// Events per second.
+    pub fn rate(&mut self) -> f64 {
+        let now = Instant::now();
+        self.roll(now);
+        match self.last_rate {
+            Some(rate) => rate,
+            None => {
+                let elapsed = now.duration_since(self.window_start).as_secs_f64();
+                if elapsed > 0.0 {
+                    self.window_count as f64 / elapsed
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+}
+
 #[pin_project]
 pub struct NowOrNever<F: Future> {
     #[pin]
@@ -6,6 +6,7 @@ use serde_json::json;
 use sodiumoxide::crypto::sign;
 
 use crate::db::{User, UserId};
+use crate::logging;
 use crate::port::{ReadPort, WritePort};
 use crate::util::SimpleResult;
 use crate::{concat, State};
@@ -37,20 +38,24 @@ fn parse_signed_name(signed_name: &str, who: &UserId) -> SimpleResult<String> {
     let signed_name = Vec::from_hex(signed_name).map_err(|_| "not a valid hex string")?;
     let name_bytes = verify_with_magic(b"NAME:", &signed_name, &who.to_pubkey())?;
     let name = str::from_utf8(name_bytes)?;
+    validate_name(name)?;
+    Ok(name.to_string())
+}
+
+pub(crate) fn validate_name(name: &str) -> SimpleResult<()> {
     if name.is_empty() {
         Err("name is empty")?;
     }
     if name.chars().any(char::is_control) {
         Err("name cannot contain control characters")?;
     }
-    Ok(name.to_string())
+    Ok(())
 }
 
 pub(crate) async fn handle_vouch<'a>(
     mut read_port: ReadPort<'a>,
     mut write_port: WritePort<'a>,
     who_id: UserId,
-    who_name: &str,
     state: &State,
     data: VouchData,
 ) -> SimpleResult<()> {
@@ -66,15 +71,22 @@ pub(crate) async fn handle_vouch<'a>(
     state
         .db
         .write(true, |db| {
-            db.users.entry(data.who).or_insert_with(|| User {
-                trusted_by: Some(who_id),
-                name: vouchee_name.clone(),
-                client_stats: Default::default(),
-                server_stats: Default::default(),
-            });
+            db.add_user(
+                data.who,
+                User {
+                    trusted_by: Some(who_id),
+                    name: vouchee_name.clone(),
+                    roles: state.default_roles.clone(),
+                    limits: Default::default(),
+                    cpu_usage: Default::default(),
+                    credit: Default::default(),
+                    client_stats: Default::default(),
+                    server_stats: Default::default(),
+                },
+            );
         })
         .await;
     write_port.send_json(&json!({})).await?;
-    eprintln!("[{}] vouch {}", who_name, &vouchee_name);
+    logging::info("vouch", &[("vouchee", vouchee_name.into())]);
     Ok(())
 }
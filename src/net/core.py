@@ -1,5 +1,5 @@
 import abc
-from dataclasses import dataclass
+from dataclasses import dataclass, field
 import datetime
 import json
 import socket
@@ -59,6 +59,8 @@ class PermuterData:
     compile_script: str
     source: str
     target_o_bin: bytes
+    required_tags: List[str] = field(default_factory=list)
+    docker_image: Optional[str] = None
 
 
 def permuter_data_from_json(
@@ -75,11 +77,15 @@ def permuter_data_from_json(
         compile_script=json_prop(obj, "compile_script", str),
         source=source,
         target_o_bin=target_o_bin,
+        required_tags=json_array(obj.get("required_tags", []), str),
+        docker_image=json_prop(obj, "docker_image", str)
+        if "docker_image" in obj
+        else None,
     )
 
 
 def permuter_data_to_json(perm: PermuterData) -> dict:
-    return {
+    ret: dict = {
         "base_score": perm.base_score,
         "base_hash": perm.base_hash,
         "fn_name": perm.fn_name,
@@ -89,6 +95,11 @@ def permuter_data_to_json(perm: PermuterData) -> dict:
         "stack_differences": perm.stack_differences,
         "compile_script": perm.compile_script,
     }
+    if perm.required_tags:
+        ret["required_tags"] = perm.required_tags
+    if perm.docker_image is not None:
+        ret["docker_image"] = perm.docker_image
+    return ret
 
 
 @dataclass
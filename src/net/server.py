@@ -1,5 +1,6 @@
 import base64
-from dataclasses import dataclass
+from collections import OrderedDict
+from dataclasses import dataclass, field
 import pathlib
 import queue
 import struct
@@ -7,7 +8,7 @@ import sys
 import threading
 import time
 import traceback
-from typing import BinaryIO, Dict, Optional, Set, Tuple, Union, TYPE_CHECKING
+from typing import BinaryIO, Dict, List, Optional, Set, Tuple, Union, TYPE_CHECKING
 import zlib
 
 if TYPE_CHECKING:
@@ -26,6 +27,7 @@ from .core import (
     SocketPort,
     connect,
     file_read_fixed,
+    json_array,
     json_prop,
     permuter_data_from_json,
     permuter_data_to_json,
@@ -34,6 +36,10 @@ from .core import (
 
 _HEARTBEAT_INTERVAL_SLACK_SEC: float = 50.0
 
+# Number of compressed sources and target objects to keep around, so that the
+# controller doesn't need to resend them for every permuter.
+_BLOB_CACHE_SIZE: int = 64
+
 
 @dataclass
 class Client:
@@ -107,6 +113,15 @@ class NetThreadDisconnected:
     message: Optional[str] = None
 
 
+@dataclass
+class Resumed:
+    """The connection to the controller is back, with the given permuters
+    still loaded on its side."""
+
+    net_port: SocketPort
+    handles: Set[int]
+
+
 class Heartbeat:
     pass
 
@@ -126,6 +141,7 @@ Activity = Union[
     WorkDone,
     NeedMoreWork,
     NetThreadDisconnected,
+    Resumed,
     Heartbeat,
     Shutdown,
 ]
@@ -155,6 +171,12 @@ class OutputNeedMoreWork:
     pass
 
 
+@dataclass
+class OutputNeedBlobs:
+    handle: int
+    hashes: List[str]
+
+
 @dataclass
 class OutputWork:
     handle: int
@@ -169,6 +191,7 @@ Output = Union[
     OutputInitFail,
     OutputInitSuccess,
     OutputNeedMoreWork,
+    OutputNeedBlobs,
     OutputWork,
     Shutdown,
 ]
@@ -230,6 +253,39 @@ class ServerOptions:
     num_cores: float
     max_memory_gb: float
     min_priority: float
+    tags: List[str] = field(default_factory=list)
+    docker_image: Optional[str] = None
+
+
+class BlobCache:
+    """Compressed sources and target objects by content hash, least recently
+    used first. Only accessed from the net read thread."""
+
+    _blobs: "OrderedDict[str, bytes]"
+
+    def __init__(self) -> None:
+        self._blobs = OrderedDict()
+
+    def get(self, hash: str) -> Optional[bytes]:
+        blob = self._blobs.get(hash)
+        if blob is not None:
+            self._blobs.move_to_end(hash)
+        return blob
+
+    def put(self, hash: str, blob: bytes) -> None:
+        self._blobs[hash] = blob
+        self._blobs.move_to_end(hash)
+        while len(self._blobs) > _BLOB_CACHE_SIZE:
+            self._blobs.popitem(last=False)
+
+
+@dataclass
+class _PendingAdd:
+    time_start: float
+    client: Client
+    data: dict
+    source_hash: str
+    target_o_bin_hash: str
 
 
 class NetThread:
@@ -239,16 +295,22 @@ class NetThread:
     _read_thread: "threading.Thread"
     _write_thread: "threading.Thread"
     _next_work_id: int
+    _blob_cache: BlobCache
+    _pending_adds: Dict[int, _PendingAdd]
 
     def __init__(
         self,
         port: SocketPort,
         main_queue: "queue.Queue[Activity]",
+        blob_cache: BlobCache,
+        next_work_id: int,
     ) -> None:
         self._port = port
         self._main_queue = main_queue
         self._controller_queue = queue.Queue()
-        self._next_work_id = 0
+        self._next_work_id = next_work_id
+        self._blob_cache = blob_cache
+        self._pending_adds = {}
 
         self._read_thread = threading.Thread(target=self.read_loop, daemon=True)
         self._read_thread.start()
@@ -273,7 +335,56 @@ class NetThread:
     def send_controller(self, msg: Output) -> None:
         self._controller_queue.put(msg)
 
-    def _read_one(self) -> Activity:
+    def next_work_id(self) -> int:
+        return self._next_work_id
+
+    def _make_add(
+        self,
+        handle: int,
+        time_start: float,
+        client: Client,
+        data: dict,
+        compressed_source: bytes,
+        compressed_target_o_bin: bytes,
+    ) -> Activity:
+        try:
+            source = zlib.decompress(compressed_source).decode("utf-8")
+            target_o_bin = zlib.decompress(compressed_target_o_bin)
+            permuter = permuter_data_from_json(data, source, target_o_bin)
+        except Exception as e:
+            # Client sent something illegible. This can legitimately happen if the
+            # client runs another version, but it's interesting to log.
+            traceback.print_exc()
+            return ImmediateDisconnect(
+                handle=handle,
+                client=client,
+                reason=f"Failed to parse permuter: {exception_to_string(e)}",
+            )
+
+        return AddPermuter(
+            handle=handle,
+            time_start=time_start,
+            client=client,
+            permuter_data=permuter,
+        )
+
+    def _try_add_from_cache(
+        self, handle: int, pending: _PendingAdd
+    ) -> Optional[Activity]:
+        compressed_source = self._blob_cache.get(pending.source_hash)
+        compressed_target_o_bin = self._blob_cache.get(pending.target_o_bin_hash)
+        if compressed_source is None or compressed_target_o_bin is None:
+            return None
+        return self._make_add(
+            handle,
+            pending.time_start,
+            pending.client,
+            pending.data,
+            compressed_source,
+            compressed_target_o_bin,
+        )
+
+    def _read_one(self) -> Optional[Activity]:
         assert self._port is not None
 
         msg = self._port.receive_json()
@@ -297,31 +408,57 @@ class NetThread:
             client_name = json_prop(msg, "client_name", str)
             client = Client(client_id, client_name)
             data = json_prop(msg, "data", dict)
-            compressed_source = self._port.receive()
-            compressed_target_o_bin = self._port.receive()
 
-            try:
-                source = zlib.decompress(compressed_source).decode("utf-8")
-                target_o_bin = zlib.decompress(compressed_target_o_bin)
-                permuter = permuter_data_from_json(data, source, target_o_bin)
-            except Exception as e:
-                # Client sent something illegible. This can legitimately happen if the
-                # client runs another version, but it's interesting to log.
-                traceback.print_exc()
-                return ImmediateDisconnect(
-                    handle=handle,
-                    client=client,
-                    reason=f"Failed to parse permuter: {exception_to_string(e)}",
+            if "blobs" not in msg:
+                compressed_source = self._port.receive()
+                compressed_target_o_bin = self._port.receive()
+                return self._make_add(
+                    handle,
+                    time_start,
+                    client,
+                    data,
+                    compressed_source,
+                    compressed_target_o_bin,
                 )
 
-            return AddPermuter(
-                handle=handle,
+            # The controller sent content hashes instead. Ask for the blobs we
+            # don't have, and hold off on adding the permuter until they come.
+            blobs = json_prop(msg, "blobs", dict)
+            pending = _PendingAdd(
                 time_start=time_start,
                 client=client,
-                permuter_data=permuter,
+                data=data,
+                source_hash=json_prop(blobs, "source", str),
+                target_o_bin_hash=json_prop(blobs, "target_o_bin", str),
             )
+            add = self._try_add_from_cache(handle, pending)
+            if add is not None:
+                return add
+            missing = [
+                hash
+                for hash in dict.fromkeys(
+                    [pending.source_hash, pending.target_o_bin_hash]
+                )
+                if self._blob_cache.get(hash) is None
+            ]
+            self._pending_adds[handle] = pending
+            self.send_controller(OutputNeedBlobs(handle=handle, hashes=missing))
+            return None
+
+        elif msg_type == "blobs":
+            hashes = json_prop(msg, "hashes", list)
+            for hash in hashes:
+                self._blob_cache.put(hash, self._port.receive())
+            pending_add = self._pending_adds.pop(handle, None)
+            if pending_add is None:
+                return None
+            add = self._try_add_from_cache(handle, pending_add)
+            if add is None:
+                raise Exception("Controller did not send all requested blobs")
+            return add
 
         elif msg_type == "remove":
+            self._pending_adds.pop(handle, None)
             return RemovePermuter(handle=handle)
 
         else:
@@ -331,7 +468,8 @@ class NetThread:
         try:
             while True:
                 msg = self._read_one()
-                self._main_queue.put(msg)
+                if msg is not None:
+                    self._main_queue.put(msg)
         except EOFError:
             self._main_queue.put(NetThreadDisconnected(graceful=True))
         except ServerError as e:
@@ -382,6 +520,15 @@ class NetThread:
         elif isinstance(item, OutputNeedMoreWork):
             self._port.send_json({"type": "need_work"})
 
+        elif isinstance(item, OutputNeedBlobs):
+            self._port.send_json(
+                {
+                    "type": "need_blobs",
+                    "permuter": item.handle,
+                    "hashes": item.hashes,
+                }
+            )
+
         elif isinstance(item, OutputWork):
             overhead_us = int((time.time() - item.time_start) * 10 ** 6) - item.time_us
             self._port.send_json(
@@ -429,9 +576,14 @@ class ServerInner:
     _heartbeat_interval: float
     _last_heartbeat: float
     _last_heartbeat_lock: threading.Lock
+    _heartbeat_stop: threading.Event
+    _heartbeat_thread: threading.Thread
     _active: Set[int]
+    _loaded: Dict[int, str]
     _time_starts: Dict[int, float]
     _token: CancelToken
+    _blob_cache: BlobCache
+    _net_connected: bool
 
     def __init__(
         self,
@@ -439,25 +591,22 @@ class ServerInner:
         evaluator_port: "DockerPort",
         io_queue: "queue.Queue[IoActivity]",
         heartbeat_interval: float,
+        blob_cache: BlobCache,
     ) -> None:
         self._evaluator_port = evaluator_port
         self._main_queue = queue.Queue()
         self._io_queue = io_queue
         self._active = set()
+        self._loaded = {}
         self._time_starts = {}
         self._token = CancelToken()
+        self._blob_cache = blob_cache
+        self._net_connected = True
 
-        self._net_thread = NetThread(net_port, self._main_queue)
+        self._net_thread = NetThread(net_port, self._main_queue, blob_cache, 0)
 
-        # Start a thread for checking heartbeats.
-        self._heartbeat_interval = heartbeat_interval
-        self._last_heartbeat = time.time()
         self._last_heartbeat_lock = threading.Lock()
-        self._heartbeat_stop = threading.Event()
-        self._heartbeat_thread = threading.Thread(
-            target=self._heartbeat_loop, daemon=True
-        )
-        self._heartbeat_thread.start()
+        self._start_heartbeat(heartbeat_interval)
 
         # Start a thread for reading evaluator results and sending them on to
         # the main loop queue.
@@ -470,6 +619,17 @@ class ServerInner:
         self._main_thread = threading.Thread(target=self._main_loop, daemon=True)
         self._main_thread.start()
 
+    def _start_heartbeat(self, heartbeat_interval: float) -> None:
+        """Start a thread for checking heartbeats."""
+        self._heartbeat_interval = heartbeat_interval
+        with self._last_heartbeat_lock:
+            self._last_heartbeat = time.time()
+        self._heartbeat_stop = threading.Event()
+        self._heartbeat_thread = threading.Thread(
+            target=self._heartbeat_loop, daemon=True
+        )
+        self._heartbeat_thread.start()
+
     def _send_controller(self, msg: Output) -> None:
         self._net_thread.send_controller(msg)
 
@@ -555,6 +715,7 @@ class ServerInner:
                 self._need_work()
                 return
 
+            self._loaded[handle] = msg.base_hash
             self._send_controller(
                 OutputInitSuccess(
                     handle=handle,
@@ -566,7 +727,11 @@ class ServerInner:
 
         elif isinstance(msg, WorkDone):
             handle = int(msg.perm_id)
-            time_start = self._time_starts.pop(msg.id)
+            time_start = self._time_starts.pop(msg.id, None)
+            if time_start is None:
+                # Work from before a reconnect, which the controller has
+                # forgotten about.
+                return
             if handle not in self._active:
                 self._need_work()
                 return
@@ -593,8 +758,27 @@ class ServerInner:
             self._need_work()
 
         elif isinstance(msg, NetThreadDisconnected):
+            # Both net threads notice a disconnect, but it only needs handling
+            # once.
+            if not self._net_connected:
+                return
+            self._net_connected = False
             self._send_io_global(IoServerFailed(msg.graceful, msg.message))
 
+        elif isinstance(msg, Resumed):
+            # Drop what the controller no longer has us working on, and
+            # results of work handed out before the disconnect.
+            for handle in list(self._active):
+                if handle not in msg.handles:
+                    self._remove(handle)
+                    self._send_io(handle, IoDisconnect("disconnected"))
+            self._time_starts.clear()
+            next_work_id = self._net_thread.next_work_id()
+            self._net_thread = NetThread(
+                msg.net_port, self._main_queue, self._blob_cache, next_work_id
+            )
+            self._net_connected = True
+
         else:
             static_assert_unreachable(msg)
 
@@ -604,6 +788,7 @@ class ServerInner:
     def _remove(self, handle: int) -> None:
         self._evaluator_port.send_json({"type": "remove", "permuter": str(handle)})
         self._active.remove(handle)
+        self._loaded.pop(handle, None)
 
     def _send_permuter(self, perm_id: str, perm: PermuterData) -> None:
         self._evaluator_port.send_json(
@@ -705,6 +890,34 @@ class ServerInner:
         assert not self._token.cancelled
         self._main_queue.put(Disconnect(handle=handle))
 
+    def loaded(self) -> List[dict]:
+        """The permuters that are loaded, with their hashes, for resuming."""
+        loaded = dict(self._loaded)
+        return [{"permuter": handle, "hash": hash} for handle, hash in loaded.items()]
+
+    def detach_net(self) -> None:
+        """Stop talking to the controller, but keep the evaluator running with
+        its permuters loaded, so that they can be resumed."""
+        assert not self._token.cancelled
+        self._heartbeat_stop.set()
+        self._heartbeat_thread.join()
+        self._net_thread.stop()
+
+    def attach_net(
+        self, net_port: SocketPort, heartbeat_interval: float, resumed: Set[int]
+    ) -> None:
+        """Talk to the controller again after detach_net, keeping the given
+        permuters."""
+        assert not self._token.cancelled
+        self._main_queue.put(Resumed(net_port=net_port, handles=resumed))
+        self._start_heartbeat(heartbeat_interval)
+
+    def report_lost(self, handles: List[int]) -> None:
+        """Tell the controller that permuters it thinks are loaded here are
+        not."""
+        for handle in handles:
+            self._send_controller(OutputDisconnect(handle=handle))
+
     def stop(self) -> None:
         assert not self._token.cancelled
         self._token.cancelled = True
@@ -896,6 +1109,10 @@ class Server:
     _options: ServerOptions
     _config: Config
     _io_queue: "queue.Queue[IoActivity]"
+    _blob_cache: BlobCache
+    _docker_image: Optional[str]
+    _resume_token: Optional[str]
+    _suspended: bool
 
     def __init__(
         self,
@@ -907,37 +1124,92 @@ class Server:
         self._options = options
         self._config = config
         self._io_queue = io_queue
+        self._blob_cache = BlobCache()
+        self._docker_image = None
+        self._resume_token = None
+        self._suspended = False
 
     def start(self) -> None:
-        assert self._server is None
+        """Connect to the controller, resuming the previous session if we
+        were suspended."""
+        assert self._server is None or self._suspended
+        suspended = self._server
 
         net_port = connect(self._config)
-        net_port.send_json(
-            {
-                "method": "connect_server",
-                "min_priority": self._options.min_priority,
-                "num_cores": self._options.num_cores,
-            }
-        )
+        request: dict = {
+            "method": "connect_server",
+            "min_priority": self._options.min_priority,
+            "num_cores": self._options.num_cores,
+            "blob_cache": True,
+            "tags": self._options.tags,
+            "resumable": True,
+        }
+        image_name = self._options.docker_image
+        if image_name is not None:
+            request["docker_images"] = [image_name]
+        if suspended is not None:
+            assert self._resume_token is not None
+            request["resume"] = self._resume_token
+            request["loaded"] = suspended.loaded()
+        net_port.send_json(request)
         obj = net_port.receive_json()
-        docker_image = json_prop(obj, "docker_image", str)
+        if image_name is not None:
+            images = json_prop(obj, "docker_images", dict)
+            docker_image = json_prop(images, image_name, str)
+        else:
+            docker_image = json_prop(obj, "docker_image", str)
         heartbeat_interval = json_prop(obj, "heartbeat_interval", float)
+        self._resume_token = None
+        if "resume_token" in obj:
+            self._resume_token = json_prop(obj, "resume_token", str)
+
+        lost: List[int] = []
+        if suspended is not None:
+            resumed: List[int] = []
+            if "resumed" in obj:
+                resumed = json_array(json_prop(obj, "resumed", list), int)
+            if docker_image == self._docker_image:
+                suspended.attach_net(net_port, heartbeat_interval, set(resumed))
+                self._suspended = False
+                return
+            # The evaluator runs another image than the controller now wants,
+            # so the resumed permuters can't be kept.
+            self.stop()
+            lost = resumed
 
         evaluator_port = _start_evaluator(docker_image, self._options)
 
         try:
             self._server = ServerInner(
-                net_port, evaluator_port, self._io_queue, heartbeat_interval
+                net_port,
+                evaluator_port,
+                self._io_queue,
+                heartbeat_interval,
+                self._blob_cache,
             )
         except:
             evaluator_port.shutdown()
             raise
+        self._docker_image = docker_image
+        self._server.report_lost(lost)
+
+    def suspend(self) -> None:
+        """Disconnect from the controller after the connection dropped, but
+        keep loaded permuters around if the controller lets us resume them."""
+        if self._server is None:
+            return
+        if self._resume_token is None:
+            self.stop()
+            return
+        self._server.detach_net()
+        self._suspended = True
 
     def stop(self) -> None:
         if self._server is None:
             return
         self._server.stop()
         self._server = None
+        self._suspended = False
 
     def remove_permuter(self, handle: PermuterHandle) -> None:
         if self._server is not None and not handle[1].cancelled:
@@ -243,6 +243,15 @@ class Permuter:
             return itertools.repeat(self._force_seed)
         return iter([self._force_seed])
 
+    def network_seed_count(self) -> Optional[int]:
+        """If the permuter@home controller may pick seeds on our behalf, the
+        number of seeds to pick from. This is the case when randomizing, since
+        then seeds repeat anyway, so it doesn't matter that local workers may
+        try the same ones."""
+        if self._force_seed is None and self._permutations.is_random():
+            return self._permutations.perm_count
+        return None
+
     def try_eval_candidate(self, seed: int) -> EvalResult:
         """Evaluate a seed for the permuter."""
         try: